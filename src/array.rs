@@ -0,0 +1,981 @@
+use std::cell::Cell;
+use std::fmt;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use crate::{Event, Interest, Source, Sources, State, Timeout};
+
+/// Returned when registering a source in a full [`PollArray`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapacityExceeded;
+
+impl fmt::Display for CapacityExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "source capacity exceeded")
+    }
+}
+
+impl std::error::Error for CapacityExceeded {}
+
+/// Keeps track of at most `N` sources to poll, without ever allocating.
+///
+/// This is a fixed-capacity variant of [`Sources`], for contexts where allocating isn't an
+/// option. Instead of being collected into a buffer, events are read back with
+/// [`PollArray::events`] after each wait.
+#[derive(Debug, Clone)]
+pub struct PollArray<K, const N: usize> {
+    sources: Sources<K, N>,
+    /// Slots to free on the next call to [`PollArray::apply_deferred`].
+    deferred: [Cell<bool>; N],
+}
+
+impl<K, const N: usize> PollArray<K, N> {
+    /// Creates a new, empty array of sources to poll.
+    pub fn new() -> Self {
+        Self {
+            sources: Sources::with_inline(),
+            deferred: std::array::from_fn(|_| Cell::new(false)),
+        }
+    }
+
+    /// Return the number of registered sources.
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    /// Return whether the array is empty.
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Return whether the array is full, ie. whether registering another source would fail.
+    pub fn is_full(&self) -> bool {
+        self.sources.free.is_empty() && self.sources.list.len() == N
+    }
+
+    /// Wait for readiness events on the registered sources. If no event
+    /// is returned within the given timeout, returns an error of kind [`io::ErrorKind::TimedOut`],
+    /// unless no sources are registered, in which case `Ok(0)` is returned once the timeout
+    /// expires.
+    ///
+    /// Returns the number of sources with events, which can then be read back with
+    /// [`Self::events()`].
+    pub fn poll(&mut self, timeout: impl Into<Timeout>) -> Result<usize, io::Error> {
+        self.sources.poll_raw(timeout, None)
+    }
+
+    /// Wait for readiness events on the registered sources, with a timeout.
+    ///
+    /// This is identical to [`Self::poll()`], except that you must specify a timeout with this.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<usize, io::Error> {
+        self.poll(timeout)
+    }
+
+    /// Wait for readiness events on the registered sources, or until the call is interrupted.
+    ///
+    /// This is identical to [`Self::poll()`], except that you cannot specify a timeout with this.
+    pub fn wait(&mut self) -> Result<usize, io::Error> {
+        self.poll(Timeout::Never)
+    }
+
+    /// Return whether the last wait returned any events. This is `false` after a failed wait.
+    pub fn has_events(&self) -> bool {
+        matches!(self.state(), State::Ready { .. })
+    }
+
+    /// Return the state of the events left by the last wait. See [`State`].
+    pub fn state(&self) -> State {
+        self.sources.state()
+    }
+
+    /// Return why the last wait failed, or `None` if it succeeded or timed out.
+    pub fn last_wait_error(&self) -> Option<io::ErrorKind> {
+        self.sources.last_wait_error()
+    }
+
+    /// Iterate over the events returned by the last wait.
+    pub fn events(&self) -> ArrayEvents<'_, K> {
+        // Stop looking once all the sources with events are found. The count is kept in sync
+        // with events being discarded, but scan everything if it isn't known.
+        let remaining = match self.sources.state {
+            State::Ready { count } => Some(count),
+            _ => None,
+        };
+        ArrayEvents {
+            index: &self.sources.index,
+            list: &self.sources.list,
+            ix: 0,
+            remaining,
+        }
+    }
+
+    /// Iterate over the sources with events from the last wait, with mutable access to them.
+    ///
+    /// Unlike [`Self::events()`], this allows changing the interests of a source while its
+    /// event is handled, eg. to stop waiting for writability once a buffer is flushed. Changes
+    /// take effect on the next wait, and leave the events of other sources alone.
+    pub fn events_mut(&mut self) -> impl Iterator<Item = (&K, &mut Source)> {
+        let sources = &mut self.sources;
+        sources.changed = true;
+
+        sources
+            .index
+            .iter()
+            .zip(sources.list.iter_mut())
+            .zip(sources.ready.iter_mut())
+            .filter_map(|((key, source), ready)| match key {
+                Some(key) if source.0.revents != 0 => {
+                    *ready = 0;
+                    Some((key, source))
+                }
+                _ => None,
+            })
+    }
+
+    /// Iterate over the events returned by the last wait, discarding each as it is yielded.
+    ///
+    /// Unlike [`Self::events()`], events aren't reported again afterwards, and
+    /// [`Self::has_events()`] turns false once all are consumed. Events that weren't consumed
+    /// are kept.
+    ///
+    /// This can be used to handle a bounded number of events at a time, so that a busy
+    /// source doesn't hold up other work: the rest are yielded by the next call, without
+    /// polling again. Waiting replaces the remaining events with fresh ones instead; sources
+    /// that are still ready are reported again.
+    ///
+    /// ```
+    /// use std::io::{self, prelude::*};
+    /// use std::os::unix::net::UnixStream;
+    ///
+    /// use popol::{interest, PollArray, Timeout};
+    ///
+    /// let pairs = [UnixStream::pair()?, UnixStream::pair()?];
+    /// let mut sources = PollArray::<_, 2>::new();
+    ///
+    /// for (key, (writer, reader)) in pairs.iter().enumerate() {
+    ///     sources.register(key, reader, interest::READ).unwrap();
+    ///     (&*writer).write_all(&[1])?;
+    /// }
+    /// sources.poll(Timeout::from_secs(1))?;
+    ///
+    /// while sources.has_events() {
+    ///     for _event in sources.drain_events().take(1) {
+    ///         // Handle the event.
+    ///     }
+    ///     // Do other work.
+    /// }
+    /// # Ok::<(), io::Error>(())
+    /// ```
+    pub fn drain_events(&mut self) -> impl Iterator<Item = Event<&K>> {
+        let sources = &mut self.sources;
+        let state = &mut sources.state;
+
+        sources
+            .index
+            .iter()
+            .zip(sources.list.iter_mut())
+            .filter_map(move |(key, source)| match key {
+                Some(key) if source.0.revents != 0 => {
+                    let event = Event {
+                        key,
+                        source: *source,
+                    };
+                    source.0.revents = 0;
+                    state.discard_event();
+
+                    Some(event)
+                }
+                _ => None,
+            })
+    }
+
+    /// Discard the events returned by the last wait, so that [`Self::events()`] yields
+    /// nothing until the next wait. Returns the number of sources whose events were
+    /// discarded.
+    ///
+    /// Events are otherwise only replaced by the next wait; changing the registry never
+    /// discards them.
+    ///
+    /// The number of sources with events is kept in sync as events are discarded, so this
+    /// stops once they are all found, and returns right away when there aren't any.
+    pub fn reset(&mut self) -> usize {
+        let count = match self.sources.state {
+            State::Ready { count } => count,
+            _ => return 0,
+        };
+        let mut discarded = 0;
+
+        for source in self.sources.list.iter_mut() {
+            if discarded == count {
+                break;
+            }
+            if source.0.revents != 0 {
+                source.0.revents = 0;
+                discarded += 1;
+            }
+        }
+        self.sources.state = State::Idle;
+
+        discarded
+    }
+}
+
+impl<K: Clone + PartialEq, const N: usize> PollArray<K, N> {
+    /// Register a new source, with the given key, and wait for the specified events.
+    /// Fails if the array is full.
+    ///
+    /// Care must be taken not to register the same source twice.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if a source is already registered with this key.
+    pub fn register(
+        &mut self,
+        key: K,
+        fd: &impl AsRawFd,
+        events: impl Into<Interest>,
+    ) -> Result<(), CapacityExceeded> {
+        debug_assert!(
+            self.sources.find(&key).is_none(),
+            "registering duplicate key"
+        );
+
+        if self.is_full() {
+            return Err(CapacityExceeded);
+        }
+        let ix = self
+            .sources
+            .insert(key, Source::registered(fd, events.into()));
+        self.deferred[ix].set(false);
+
+        Ok(())
+    }
+
+    /// Unregister a source, given its key. Returns the source, if it was registered.
+    pub fn unregister(&mut self, key: &K) -> Option<Source> {
+        let ix = self.sources.find(key)?;
+        let source = self.sources.list[ix];
+
+        self.deferred[ix].set(false);
+        self.sources.remove(ix);
+
+        Some(source)
+    }
+
+    /// Change the key of a registered source. See [`Sources::rekey`].
+    pub fn rekey(&mut self, old: &K, new: K) -> bool {
+        self.sources.rekey(old, new)
+    }
+
+    /// Unregister a source on the next call to [`Self::apply_deferred`]. Returns whether the
+    /// source was found.
+    ///
+    /// Unlike [`Self::unregister`], this can be called while iterating over events, eg. to
+    /// unregister the source whose event is being handled. The source keeps being polled until
+    /// the deferred unregistrations are applied.
+    pub fn defer_unregister(&self, key: &K) -> bool {
+        if let Some(ix) = self.sources.find(key) {
+            self.deferred[ix].set(true);
+            return true;
+        }
+        false
+    }
+
+    /// Unregister the sources passed to [`Self::defer_unregister`]. Returns the number of
+    /// sources that were unregistered.
+    pub fn apply_deferred(&mut self) -> usize {
+        let mut removed = 0;
+
+        for ix in 0..N {
+            if self.deferred[ix].replace(false) {
+                self.sources.remove(ix);
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Add events to poll for on a source identified by its key. Events pending from the last
+    /// wait are kept, including the source's own: the change takes effect on the next wait.
+    pub fn add_interest(&mut self, key: &K, events: impl Into<Interest>) -> bool {
+        self.sources.add_interest(key, events)
+    }
+
+    /// Remove events to poll for on a source identified by its key. Like
+    /// [`Self::add_interest`], this leaves pending events alone.
+    pub fn remove_interest(&mut self, key: &K, events: impl Into<Interest>) -> bool {
+        self.sources.remove_interest(key, events)
+    }
+
+    /// Replace the events to poll for on a source identified by its key.
+    /// See [`Sources::set_interest`].
+    pub fn set_interest(&mut self, key: &K, events: impl Into<Interest>) -> bool {
+        self.sources.set_interest(key, events)
+    }
+
+    /// Get a source by key.
+    pub fn get(&mut self, key: &K) -> Option<&Source> {
+        self.sources.get(key)
+    }
+
+    /// Get a source by key, mutably. Changing the source leaves the pending events of other
+    /// sources alone.
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut Source> {
+        self.sources.get_mut(key)
+    }
+
+    /// Discard the events returned by the last wait, like [`Self::reset()`], and return the
+    /// keys of the sources whose events were discarded. Unlike the rest of this type, this
+    /// allocates, and is meant for diagnosing events that went unhandled.
+    pub fn reset_collect(&mut self) -> Vec<K> {
+        let keys = self.events().map(|e| e.key.clone()).collect();
+        self.reset();
+
+        keys
+    }
+}
+
+/// Iterator over the events of a [`PollArray`]. See [`PollArray::events`].
+#[derive(Debug, Clone)]
+pub struct ArrayEvents<'a, K> {
+    index: &'a [Option<K>],
+    list: &'a [Source],
+    ix: usize,
+    /// Number of events left to find, if known.
+    remaining: Option<usize>,
+}
+
+impl<'a, K> Iterator for ArrayEvents<'a, K> {
+    type Item = Event<&'a K>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.remaining != Some(0) {
+            let source = self.list.get(self.ix)?;
+            let key = &self.index[self.ix];
+            self.ix += 1;
+
+            if let (Some(key), true) = (key, source.0.revents != 0) {
+                if let Some(remaining) = &mut self.remaining {
+                    *remaining -= 1;
+                }
+
+                return Some(Event {
+                    key,
+                    source: *source,
+                });
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.remaining {
+            Some(remaining) => (remaining, Some(remaining)),
+            None => (0, Some(self.list.len().saturating_sub(self.ix))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interest;
+    use crate::tests::allocations;
+
+    use std::io::prelude::*;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_readable() -> io::Result<()> {
+        let pairs = (0..3)
+            .map(|_| UnixStream::pair())
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut sources = PollArray::<_, 3>::new();
+
+        for (key, (_, reader)) in pairs.iter().enumerate() {
+            sources.register(key, reader, interest::READ).unwrap();
+        }
+
+        let err = sources.poll(Timeout::from_millis(1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(sources.events().count(), 0);
+
+        let allocated = allocations();
+        for (key, pair) in pairs.iter().enumerate() {
+            let (mut writer, mut reader) = (&pair.0, &pair.1);
+            let mut buf = [0u8; 1];
+
+            writer.write_all(&[key as u8])?;
+            assert_eq!(sources.poll(Timeout::from_millis(1))?, 1);
+
+            let mut events = sources.events();
+            let event = events.next().unwrap();
+
+            assert_eq!(event.key, &key);
+            assert!(event.is_readable() && !event.is_writable() && !event.is_hangup());
+            assert!(events.next().is_none());
+
+            assert_eq!(reader.read(&mut buf[..])?, 1);
+            assert_eq!(buf[0], key as u8);
+        }
+        assert_eq!(allocations(), allocated, "nothing was allocated");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_capacity() -> io::Result<()> {
+        let (_writer, reader) = UnixStream::pair()?;
+        let mut sources = PollArray::<_, 2>::new();
+
+        assert_eq!(sources.register(0, &reader, interest::READ), Ok(()));
+        assert_eq!(sources.register(1, &reader, interest::READ), Ok(()));
+        assert!(sources.is_full());
+        assert_eq!(
+            sources.register(2, &reader, interest::READ),
+            Err(CapacityExceeded)
+        );
+        assert_eq!(sources.len(), 2);
+
+        sources.unregister(&0);
+        assert!(!sources.is_full());
+        assert_eq!(sources.register(3, &reader, interest::READ), Ok(()));
+
+        let mut empty = PollArray::<(), 0>::new();
+        assert_eq!(
+            empty.register((), &reader, interest::READ),
+            Err(CapacityExceeded)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset() -> io::Result<()> {
+        let pairs = (0..3)
+            .map(|_| UnixStream::pair())
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut sources = PollArray::<_, 3>::new();
+
+        for (key, pair) in pairs.iter().enumerate() {
+            let (mut writer, reader) = (&pair.0, &pair.1);
+            sources.register(key, reader, interest::READ).unwrap();
+
+            if key != 1 {
+                writer.write_all(&[1])?;
+            }
+        }
+        assert_eq!(sources.poll(Timeout::from_millis(1))?, 2);
+
+        // Changing the registry doesn't discard events.
+        sources.remove_interest(&0, interest::READ);
+        assert_eq!(sources.events().count(), 2);
+
+        assert_eq!(sources.reset_collect(), vec![0, 2]);
+        assert_eq!(sources.events().count(), 0);
+        assert_eq!(sources.reset(), 0);
+
+        assert_eq!(sources.poll(Timeout::from_millis(1))?, 1);
+        assert_eq!(sources.reset(), 1);
+        assert!(sources.reset_collect().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_events_stop_early() -> io::Result<()> {
+        let pairs = (0..128)
+            .map(|_| UnixStream::pair())
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut sources = PollArray::<_, 128>::new();
+
+        for (key, (_, reader)) in pairs.iter().enumerate() {
+            sources.register(key, reader, interest::READ).unwrap();
+        }
+        for (writer, _) in &pairs[..3] {
+            let mut writer = writer;
+            writer.write_all(&[1])?;
+        }
+        assert_eq!(sources.poll(Timeout::from_millis(1))?, 3);
+
+        let mut events = sources.events();
+        assert_eq!(events.size_hint(), (3, Some(3)));
+
+        for key in 0..3 {
+            assert_eq!(events.next().map(|e| *e.key), Some(key));
+            assert_eq!(events.size_hint(), (2 - key, Some(2 - key)));
+        }
+        // The idle sources behind the ready ones are never looked at.
+        assert!(events.next().is_none());
+        assert_eq!(events.ix, 3);
+
+        // Discarding events keeps the count in sync.
+        assert_eq!(sources.drain_events().take(1).count(), 1);
+        assert_eq!(sources.events().size_hint(), (2, Some(2)));
+        sources.unregister(&1);
+        assert_eq!(sources.events().size_hint(), (1, Some(1)));
+        assert_eq!(
+            sources.events().map(|e| *e.key).collect::<Vec<_>>(),
+            vec![2]
+        );
+
+        sources.reset();
+        assert_eq!(sources.events().size_hint().0, 0);
+        assert!(sources.events().next().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_sparse() -> io::Result<()> {
+        let pairs = (0..64)
+            .map(|_| UnixStream::pair())
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut sources = PollArray::<_, 64>::new();
+
+        for (key, (_, reader)) in pairs.iter().enumerate() {
+            sources.register(key, reader, interest::READ).unwrap();
+        }
+        for key in [3, 40, 41] {
+            (&pairs[key].0).write_all(&[1])?;
+        }
+        assert_eq!(sources.poll(Timeout::from_millis(1))?, 3);
+        assert_eq!(sources.drain_events().next().map(|e| *e.key), Some(3));
+
+        assert_eq!(sources.reset(), 2);
+        assert!(!sources.has_events());
+        assert_eq!(sources.state(), State::Idle);
+        assert_eq!(sources.events().count(), 0);
+        assert!(sources.sources.list.iter().all(|s| s.0.revents == 0));
+        assert_eq!(sources.reset(), 0);
+
+        // Sources that are still ready are reported by the next wait.
+        assert_eq!(sources.poll(Timeout::from_millis(1))?, 3);
+        assert!(sources.has_events());
+        assert_eq!(
+            sources.events().map(|e| *e.key).collect::<Vec<_>>(),
+            vec![3, 40, 41]
+        );
+
+        // After a wait times out, there is nothing to discard.
+        for key in [3, 40, 41] {
+            (&pairs[key].1).read_exact(&mut [0])?;
+        }
+        assert!(sources.poll(Timeout::from_millis(1)).is_err());
+        assert_eq!(sources.reset(), 0);
+        assert!(!sources.has_events());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interrupted() -> io::Result<()> {
+        extern "C" fn handler(_: libc::c_int) {}
+
+        let (mut writer, reader) = UnixStream::pair()?;
+        let (_idle, idle) = UnixStream::pair()?;
+        let mut sources = PollArray::<_, 2>::new();
+
+        sources.register("reader", &reader, interest::READ).unwrap();
+        sources.register("idle", &idle, interest::READ).unwrap();
+        writer.write_all(&[1])?;
+
+        assert_eq!(sources.poll(Timeout::from_millis(1))?, 1);
+        assert!(sources.has_events());
+        assert_eq!(sources.last_wait_error(), None);
+
+        // Interrupt the next wait with a signal.
+        sources.unregister(&"reader");
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handler as libc::sighandler_t;
+            assert_eq!(
+                libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()),
+                0
+            );
+        }
+        let thread = unsafe { libc::pthread_self() };
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            unsafe { libc::pthread_kill(thread, libc::SIGUSR1) };
+        });
+        let err = sources.poll(Timeout::from_secs(6)).unwrap_err();
+        handle.join().unwrap();
+
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert_eq!(sources.last_wait_error(), Some(io::ErrorKind::Interrupted));
+        assert!(!sources.has_events());
+        assert_eq!(sources.events().count(), 0);
+
+        // The next successful wait clears the error.
+        writer.write_all(&[1])?;
+        sources.register("reader", &reader, interest::READ).unwrap();
+        assert_eq!(sources.poll(Timeout::from_millis(1))?, 1);
+        assert_eq!(sources.last_wait_error(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_defer_unregister() -> io::Result<()> {
+        let pairs = (0..4)
+            .map(|_| UnixStream::pair())
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut sources = PollArray::<_, 4>::new();
+
+        for (key, pair) in pairs.iter().enumerate() {
+            let (mut writer, reader) = (&pair.0, &pair.1);
+
+            sources.register(key, reader, interest::READ).unwrap();
+            writer.write_all(&[1])?;
+        }
+        assert_eq!(sources.poll(Timeout::from_millis(1))?, 4);
+
+        // Unregister the source being handled, and a later one.
+        let mut handled = Vec::new();
+        for event in sources.events() {
+            handled.push(*event.key);
+
+            if *event.key == 1 {
+                assert!(sources.defer_unregister(&1));
+                assert!(sources.defer_unregister(&3));
+            }
+        }
+        assert_eq!(handled, vec![0, 1, 2, 3]);
+        assert!(!sources.defer_unregister(&42));
+        assert_eq!(sources.apply_deferred(), 2);
+        assert_eq!(sources.apply_deferred(), 0);
+        assert_eq!(sources.len(), 2);
+
+        sources.poll(Timeout::from_millis(1))?;
+        let keys = sources.events().map(|e| *e.key).collect::<Vec<_>>();
+        assert_eq!(keys, vec![0, 2]);
+
+        // A deferred unregistration doesn't outlive its source.
+        assert!(sources.defer_unregister(&0));
+        sources.unregister(&0);
+        sources.register(4, &pairs[0].1, interest::READ).unwrap();
+        assert_eq!(sources.apply_deferred(), 0);
+        assert_eq!(sources.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_state() -> io::Result<()> {
+        let pairs = (0..4)
+            .map(|_| UnixStream::pair())
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut sources = PollArray::<_, 4>::new();
+        let mut rng = 0x2545f491u32;
+        let mut buf = [0u8; 16];
+
+        for pair in &pairs {
+            pair.1.set_nonblocking(true)?;
+        }
+        assert_eq!(sources.state(), State::Idle);
+
+        for _ in 0..512 {
+            // A small xorshift generator, to get the same sequence of operations every time.
+            rng ^= rng << 13;
+            rng ^= rng >> 17;
+            rng ^= rng << 5;
+
+            let key = rng as usize % pairs.len();
+            let (mut writer, mut reader) = (&pairs[key].0, &pairs[key].1);
+
+            match (rng >> 8) % 7 {
+                0 => writer.write_all(&[1])?,
+                1 => {
+                    reader.read(&mut buf).ok();
+                }
+                2 => {
+                    sources.poll(Timeout::from_millis(0)).ok();
+                }
+                3 => {
+                    sources.unregister(&key);
+                }
+                4 => {
+                    if sources.get(&key).is_none() {
+                        sources.register(key, reader, interest::READ).unwrap();
+                    }
+                }
+                5 => {
+                    sources.reset();
+                }
+                _ => {
+                    sources.remove_interest(&key, interest::READ);
+                }
+            }
+            let count = sources.events().count();
+
+            match sources.state() {
+                State::Ready { count: n } => assert_eq!(n, count),
+                _ => assert_eq!(count, 0),
+            }
+            assert_eq!(sources.has_events(), count > 0);
+            assert_eq!(
+                sources.last_wait_error().is_some(),
+                matches!(sources.state(), State::Errored(_))
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_unregister() -> io::Result<()> {
+        let (mut writer0, reader0) = UnixStream::pair()?;
+        let (mut writer1, reader1) = UnixStream::pair()?;
+        let mut sources = PollArray::<_, 2>::new();
+
+        sources
+            .register("reader0", &reader0, interest::READ)
+            .unwrap();
+        sources
+            .register("reader1", &reader1, interest::READ)
+            .unwrap();
+        sources.unregister(&"reader0");
+
+        writer0.write_all(&[0])?;
+        writer1.write_all(&[0])?;
+        sources.poll(Timeout::from_millis(1))?;
+
+        let keys = sources.events().map(|e| *e.key).collect::<Vec<_>>();
+        assert_eq!(keys, vec!["reader1"]);
+
+        sources.unregister(&"reader1");
+        assert!(sources.is_empty());
+        assert!(sources.poll(Timeout::from_millis(1)).is_ok());
+        assert_eq!(sources.events().count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_events_mut() -> io::Result<()> {
+        let (mut writer0, reader0) = UnixStream::pair()?;
+        let (mut writer1, reader1) = UnixStream::pair()?;
+        let mut sources = PollArray::<_, 2>::new();
+
+        sources
+            .register(0, &reader0, interest::READ | interest::WRITE)
+            .unwrap();
+        sources
+            .register(1, &reader1, interest::READ | interest::WRITE)
+            .unwrap();
+        writer0.write_all(&[1])?;
+        writer1.write_all(&[1])?;
+        assert_eq!(sources.poll(Timeout::from_secs(1))?, 2);
+
+        for (key, source) in sources.events_mut() {
+            assert!(source.is_writable());
+
+            if *key == 0 {
+                source.remove(interest::WRITE);
+            }
+        }
+        // Events are kept until the next wait.
+        assert!(sources.events().all(|e| e.is_readable() && e.is_writable()));
+
+        assert_eq!(sources.poll(Timeout::from_secs(1))?, 2);
+        let events = sources.events().collect::<Vec<_>>();
+        assert!(events[0].is_readable() && !events[0].is_writable());
+        assert!(events[1].is_readable() && events[1].is_writable());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_drain_events() -> io::Result<()> {
+        let pairs = (0..3)
+            .map(|_| UnixStream::pair())
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut sources = PollArray::<_, 3>::new();
+
+        for (key, pair) in pairs.iter().enumerate() {
+            let (mut writer, reader) = (&pair.0, &pair.1);
+
+            sources.register(key, reader, interest::READ).unwrap();
+            writer.write_all(&[1])?;
+        }
+        assert_eq!(sources.poll(Timeout::from_secs(1))?, 3);
+
+        // Only the consumed events are discarded.
+        let event = sources.drain_events().next().unwrap();
+        assert_eq!(event.key, &0);
+        assert!(event.is_readable());
+        assert_eq!(sources.state(), State::Ready { count: 2 });
+        assert_eq!(
+            sources.events().map(|e| *e.key).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+
+        assert_eq!(sources.drain_events().count(), 2);
+        assert!(!sources.has_events());
+        assert_eq!(sources.events().count(), 0);
+
+        // The sources weren't drained, so they're reported again by the next wait.
+        assert_eq!(sources.poll(Timeout::from_secs(1))?, 3);
+        assert_eq!(sources.drain_events().count(), 3);
+        assert_eq!(sources.state(), State::Idle);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_drain_budget() -> io::Result<()> {
+        let pairs = (0..10)
+            .map(|_| UnixStream::pair())
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut sources = PollArray::<_, 10>::new();
+        let mut handled = Vec::new();
+
+        for (key, pair) in pairs.iter().enumerate() {
+            let (mut writer, reader) = (&pair.0, &pair.1);
+
+            sources.register(key, reader, interest::READ).unwrap();
+            writer.write_all(&[1])?;
+        }
+        assert_eq!(sources.poll(Timeout::from_secs(1))?, 10);
+
+        // Every event is handled exactly once, a few at a time.
+        while sources.has_events() {
+            let batch = sources
+                .drain_events()
+                .take(3)
+                .map(|e| *e.key)
+                .collect::<Vec<_>>();
+
+            assert!(!batch.is_empty() && batch.len() <= 3);
+            handled.extend(batch);
+        }
+        assert_eq!(handled, (0..10).collect::<Vec<_>>());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rekey() -> io::Result<()> {
+        let (mut writer, reader) = UnixStream::pair()?;
+        let mut sources = PollArray::<_, 1>::new();
+
+        sources
+            .register("handshake", &reader, interest::READ)
+            .unwrap();
+        writer.write_all(&[1])?;
+        assert_eq!(sources.poll(Timeout::from_secs(1))?, 1);
+
+        // The pending event follows the source.
+        assert!(sources.rekey(&"handshake", "peer"));
+        let event = sources.events().next().unwrap();
+        assert_eq!(event.key, &"peer");
+        assert!(event.is_readable());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_events_sparse() -> io::Result<()> {
+        let pairs = (0..64)
+            .map(|_| UnixStream::pair())
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut sources = PollArray::<_, 64>::new();
+
+        for (key, pair) in pairs.iter().enumerate() {
+            sources.register(key, &pair.1, interest::READ).unwrap();
+        }
+        for key in [5, 6, 40] {
+            (&pairs[key].0).write_all(&[1])?;
+        }
+        assert_eq!(sources.poll(Timeout::from_secs(1))?, 3);
+
+        let keys = sources.events().map(|e| *e.key).collect::<Vec<_>>();
+        assert_eq!(keys, vec![5, 6, 40]);
+
+        // Without a count to go by, every source is scanned.
+        sources.sources.state = State::Idle;
+        assert_eq!(sources.events().size_hint(), (0, Some(64)));
+        let keys = sources.events().map(|e| *e.key).collect::<Vec<_>>();
+        assert_eq!(keys, vec![5, 6, 40]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_register_keeps_events() -> io::Result<()> {
+        let pairs = (0..3)
+            .map(|_| UnixStream::pair())
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut sources = PollArray::<_, 3>::new();
+
+        for (writer, _) in &pairs {
+            let mut writer = writer;
+            writer.write_all(&[1])?;
+        }
+        sources.register("a", &pairs[0].1, interest::READ).unwrap();
+        sources.register("b", &pairs[1].1, interest::READ).unwrap();
+        assert_eq!(sources.poll(Timeout::from_secs(1))?, 2);
+
+        // Handle the event of the first source, then register a new, ready source before
+        // handling the next one.
+        let keys = sources
+            .drain_events()
+            .take(1)
+            .map(|e| *e.key)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec!["a"]);
+        sources.register("c", &pairs[2].1, interest::READ).unwrap();
+        sources.unregister(&"a");
+
+        assert_eq!(sources.state(), State::Ready { count: 1 });
+        assert_eq!(
+            sources.events().map(|e| *e.key).collect::<Vec<_>>(),
+            vec!["b"]
+        );
+
+        // The new source is reported by the next wait.
+        assert_eq!(sources.poll(Timeout::from_secs(1))?, 2);
+        assert_eq!(
+            sources.events().map(|e| *e.key).collect::<Vec<_>>(),
+            vec!["b", "c"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_change_interest_keeps_events() -> io::Result<()> {
+        let (mut writer0, reader0) = UnixStream::pair()?;
+        let (mut writer1, reader1) = UnixStream::pair()?;
+        let mut sources = PollArray::<_, 2>::new();
+
+        sources.register(0, &reader0, interest::READ).unwrap();
+        sources.register(1, &reader1, interest::READ).unwrap();
+        writer0.write_all(&[1])?;
+        writer1.write_all(&[1])?;
+        assert_eq!(sources.poll(Timeout::from_secs(1))?, 2);
+
+        assert_eq!(sources.events().next().unwrap().key, &0);
+
+        // Changing one source while handling events doesn't lose the others.
+        assert!(sources.remove_interest(&1, interest::READ));
+        assert!(sources.add_interest(&0, interest::WRITE));
+        sources.get_mut(&0).unwrap().remove(interest::WRITE);
+
+        assert_eq!(sources.state(), State::Ready { count: 2 });
+        assert_eq!(
+            sources.events().map(|e| *e.key).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+
+        // The changes apply to the next wait.
+        assert_eq!(sources.poll(Timeout::from_secs(1))?, 1);
+        assert_eq!(
+            sources.events().map(|e| *e.key).collect::<Vec<_>>(),
+            vec![0]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,356 @@
+//! Futures that resolve when sources become ready, driven by the application's own wait loop.
+//!
+//! A [`Reactor`] wraps a [`SharedPoll`]. The application keeps waiting on the poll as usual,
+//! and hands the events it gets to [`Reactor::dispatch`], which wakes the futures waiting on
+//! them. Sources that aren't wrapped in [`Async`] are left alone.
+use std::future;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Poll, Waker};
+
+use crate::{interest, Event, Interest, SharedPoll};
+
+/// Wakes the futures of [`Async`] sources when events are dispatched to it.
+///
+/// Cloning a `Reactor` yields a handle to the same reactor.
+#[derive(Debug)]
+pub struct Reactor<K> {
+    poll: SharedPoll<K>,
+    pending: Arc<Mutex<Vec<Pending<K>>>>,
+}
+
+/// The state of an [`Async`] source.
+#[derive(Debug)]
+struct Pending<K> {
+    key: K,
+    /// Readiness dispatched, but not yet observed by a future.
+    ready: Interest,
+    /// Woken when the source is readable.
+    reader: Option<Waker>,
+    /// Woken when the source is writable.
+    writer: Option<Waker>,
+}
+
+impl<K> Clone for Reactor<K> {
+    fn clone(&self) -> Self {
+        Self {
+            poll: self.poll.clone(),
+            pending: self.pending.clone(),
+        }
+    }
+}
+
+impl<K: Clone + PartialEq> Reactor<K> {
+    /// Create a reactor for the sources of the given poll.
+    pub fn new(poll: SharedPoll<K>) -> Self {
+        Self {
+            poll,
+            pending: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// The poll sources are registered with.
+    pub fn poll(&self) -> &SharedPoll<K> {
+        &self.poll
+    }
+
+    /// Wake the futures waiting on the given events. Returns the number of futures woken.
+    ///
+    /// Interests are only set on a source while a future waits on it, so that an idle source
+    /// doesn't keep the wait loop busy. They are removed once dispatched.
+    ///
+    /// Futures are woken after the reactor's lock is released, so a waker may poll its future
+    /// right away.
+    pub fn dispatch(&self, events: &[Event<K>]) -> io::Result<usize> {
+        let mut wakers = Vec::new();
+        let mut result = Ok(());
+        let mut pending = self.lock();
+
+        for event in events {
+            let entry = match pending.iter_mut().find(|p| p.key == event.key) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let mut ready = interest::NONE;
+
+            if event.is_readable() || event.is_hangup() || event.is_error() {
+                ready |= interest::READ;
+            }
+            if event.is_writable() || event.is_hangup() || event.is_error() {
+                ready |= interest::WRITE;
+            }
+            entry.ready |= ready;
+
+            for (wanted, waker) in [
+                (interest::READ, &mut entry.reader),
+                (interest::WRITE, &mut entry.writer),
+            ] {
+                if !ready.contains(wanted) {
+                    continue;
+                }
+                wakers.extend(waker.take());
+            }
+            if let Err(e) = self.poll.remove_interest(event.key.clone(), ready) {
+                result = Err(e);
+                break;
+            }
+        }
+        drop(pending);
+
+        let woken = wakers.len();
+        for waker in wakers {
+            waker.wake();
+        }
+        result.map(|()| woken)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Pending<K>>> {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// An I/O source registered with a [`Reactor`], which can be waited on asynchronously.
+///
+/// The source is set to non-blocking mode, and unregistered when dropped. Hangups and errors
+/// are reported even without interests, so drop a source once it hung up.
+///
+/// # Examples
+///
+/// ```
+/// use std::io::prelude::*;
+/// use std::os::unix::net::UnixStream;
+/// use popol::asyncio::{Async, Reactor};
+/// use popol::SharedPoll;
+///
+/// let reactor = Reactor::new(SharedPoll::new("waker")?);
+/// let (writer, reader) = UnixStream::pair()?;
+/// let reader = Async::new(&reactor, "reader", reader)?;
+///
+/// // Resolves once an event for the reader is dispatched.
+/// let read = reader.read_with(|mut r| r.read(&mut [0; 32]));
+/// # drop((read, writer));
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Async<K: Clone + PartialEq, T> {
+    io: T,
+    key: K,
+    reactor: Reactor<K>,
+}
+
+impl<K: Clone + PartialEq, T: AsRawFd> Async<K, T> {
+    /// Register a source with the given reactor, under the given key.
+    pub fn new(reactor: &Reactor<K>, key: K, io: T) -> io::Result<Self> {
+        crate::replace_nonblocking(&io, true)?;
+
+        reactor.poll.register(key.clone(), &io, interest::NONE)?;
+        reactor.lock().push(Pending {
+            key: key.clone(),
+            ready: interest::NONE,
+            reader: None,
+            writer: None,
+        });
+
+        Ok(Self {
+            io,
+            key,
+            reactor: reactor.clone(),
+        })
+    }
+
+    /// The wrapped source.
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    /// Wait for the source to be readable.
+    pub async fn readable(&self) -> io::Result<()> {
+        self.ready(interest::READ).await
+    }
+
+    /// Wait for the source to be writable.
+    pub async fn writable(&self) -> io::Result<()> {
+        self.ready(interest::WRITE).await
+    }
+
+    /// Read from the source with the given operation, waiting for it to be readable each time
+    /// the operation fails with an error of kind [`io::ErrorKind::WouldBlock`].
+    pub async fn read_with<R>(&self, mut op: impl FnMut(&T) -> io::Result<R>) -> io::Result<R> {
+        loop {
+            match op(&self.io) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.readable().await?,
+                result => return result,
+            }
+        }
+    }
+
+    /// Write to the source with the given operation, waiting for it to be writable each time
+    /// the operation fails with an error of kind [`io::ErrorKind::WouldBlock`].
+    pub async fn write_with<R>(&self, mut op: impl FnMut(&T) -> io::Result<R>) -> io::Result<R> {
+        loop {
+            match op(&self.io) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.writable().await?,
+                result => return result,
+            }
+        }
+    }
+
+    /// Wait until readiness for the given interest is dispatched.
+    async fn ready(&self, events: Interest) -> io::Result<()> {
+        future::poll_fn(|cx| {
+            let mut pending = self.reactor.lock();
+            let entry = match pending.iter_mut().find(|p| p.key == self.key) {
+                Some(entry) => entry,
+                None => return Poll::Ready(Err(io::ErrorKind::NotFound.into())),
+            };
+            if entry.ready.contains(events) {
+                entry.ready &= !events;
+                return Poll::Ready(Ok(()));
+            }
+            let slot = if events == interest::READ {
+                &mut entry.reader
+            } else {
+                &mut entry.writer
+            };
+            *slot = Some(cx.waker().clone());
+
+            match self.reactor.poll.add_interest(self.key.clone(), events) {
+                Ok(()) => Poll::Pending,
+                Err(e) => Poll::Ready(Err(e)),
+            }
+        })
+        .await
+    }
+}
+
+impl<K: Clone + PartialEq, T> Drop for Async<K, T> {
+    fn drop(&mut self) {
+        self.reactor.lock().retain(|p| p.key != self.key);
+        self.reactor.poll.unregister(self.key.clone()).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Timeout;
+
+    use std::future::Future;
+    use std::io::prelude::*;
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::{Context, Wake};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Records whether it was woken.
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Records whether the reactor was locked when it was woken.
+    struct Locked {
+        reactor: Reactor<&'static str>,
+        locked: AtomicBool,
+    }
+
+    impl Wake for Locked {
+        fn wake(self: Arc<Self>) {
+            let locked = self.reactor.pending.try_lock().is_err();
+            self.locked.store(locked, Ordering::SeqCst);
+        }
+    }
+
+    /// Drive a future to completion with a manual wait loop.
+    fn run<F: Future>(reactor: &Reactor<&'static str>, fut: F) -> io::Result<F::Output> {
+        let flag = Arc::new(Flag(AtomicBool::new(true)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = Box::pin(fut);
+        let mut events = Vec::new();
+
+        loop {
+            if flag.0.swap(false, Ordering::SeqCst) {
+                if let Poll::Ready(output) = fut.as_mut().poll(&mut cx) {
+                    return Ok(output);
+                }
+            }
+            events.clear();
+            reactor
+                .poll()
+                .wait_timeout(&mut events, Duration::from_secs(6))?;
+            reactor.dispatch(&events)?;
+        }
+    }
+
+    #[test]
+    fn test_readable() -> io::Result<()> {
+        let reactor = Reactor::new(SharedPoll::new("waker")?);
+        let (mut writer, reader) = UnixStream::pair()?;
+        let reader = Async::new(&reactor, "reader", reader)?;
+
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            writer.write_all(&[1, 2, 3])
+        });
+        let mut buf = [0; 8];
+        let n = run(&reactor, reader.read_with(|mut r| r.read(&mut buf)))??;
+        handle.join().unwrap()?;
+
+        assert_eq!(&buf[..n], &[1, 2, 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_writable() -> io::Result<()> {
+        let reactor = Reactor::new(SharedPoll::new("waker")?);
+        let (writer, _reader) = UnixStream::pair()?;
+        let writer = Async::new(&reactor, "writer", writer)?;
+
+        run(&reactor, writer.writable())??;
+        assert_eq!(run(&reactor, writer.write_with(|mut w| w.write(&[1])))??, 1);
+
+        // Nothing is left pending for sources nobody waits on.
+        let mut events = Vec::new();
+        let err = reactor
+            .poll()
+            .poll(&mut events, Timeout::from_millis(10))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispatch_unlocked() -> io::Result<()> {
+        let reactor = Reactor::new(SharedPoll::new("waker")?);
+        let (mut writer, reader) = UnixStream::pair()?;
+        let reader = Async::new(&reactor, "reader", reader)?;
+        let locked = Arc::new(Locked {
+            reactor: reactor.clone(),
+            locked: AtomicBool::new(true),
+        });
+        let waker = Waker::from(locked.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = Box::pin(reader.readable());
+
+        assert!(fut.as_mut().poll(&mut cx).is_pending());
+        writer.write_all(&[1])?;
+
+        let mut events = Vec::new();
+        reactor
+            .poll()
+            .wait_timeout(&mut events, Duration::from_secs(1))?;
+        assert_eq!(reactor.dispatch(&events)?, 1);
+        assert!(!locked.locked.load(Ordering::SeqCst));
+        assert!(fut.as_mut().poll(&mut cx).is_ready());
+
+        Ok(())
+    }
+}
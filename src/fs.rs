@@ -0,0 +1,241 @@
+//! File system events as readiness events, backed by `inotify` on Linux.
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::ptr;
+
+/// Size of an event record, excluding its name.
+const HEADER_LEN: usize = mem::size_of::<libc::inotify_event>();
+
+/// A watch added to a [`Watcher`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct WatchDescriptor(libc::c_int);
+
+/// An event read from a [`Watcher`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsEvent {
+    /// The watch the event is for. Meaningless for queue overflows.
+    pub wd: WatchDescriptor,
+    /// The kind of event, as a set of `libc::IN_*` flags.
+    pub mask: u32,
+    /// Relates the two halves of a rename.
+    pub cookie: u32,
+    /// The name of the file the event is about, when watching a directory.
+    pub name: Option<OsString>,
+}
+
+impl FsEvent {
+    /// Whether the event queue overflowed, and events were dropped. Watched files should be
+    /// rescanned.
+    pub fn is_overflow(&self) -> bool {
+        self.mask & libc::IN_Q_OVERFLOW != 0
+    }
+}
+
+/// Watches files and directories for changes, becoming readable when there are events to read.
+///
+/// Register it for [`crate::interest::READ`], and call [`Watcher::read_events`] when it is
+/// readable. It is non-blocking, and closed on `exec`.
+#[derive(Debug)]
+pub struct Watcher {
+    file: File,
+}
+
+impl Watcher {
+    /// Create a watcher, with no watches.
+    pub fn new() -> io::Result<Self> {
+        // SAFETY: required for FFI; shouldn't break rust guarantees.
+        match unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(Self {
+                // SAFETY: the file descriptor was just created, and is owned by nothing else.
+                file: unsafe { File::from_raw_fd(fd) },
+            }),
+        }
+    }
+
+    /// Watch a path for the events in the given set of `libc::IN_*` flags. Watching a path
+    /// again changes the events watched, and returns the same descriptor.
+    pub fn watch(&self, path: impl AsRef<Path>, mask: u32) -> io::Result<WatchDescriptor> {
+        let path = CString::new(path.as_ref().as_os_str().as_bytes())?;
+
+        // SAFETY: required for FFI; shouldn't break rust guarantees.
+        match unsafe { libc::inotify_add_watch(self.file.as_raw_fd(), path.as_ptr(), mask) } {
+            -1 => Err(io::Error::last_os_error()),
+            wd => Ok(WatchDescriptor(wd)),
+        }
+    }
+
+    /// Remove a watch. This queues an event with the `libc::IN_IGNORED` flag.
+    pub fn unwatch(&self, wd: WatchDescriptor) -> io::Result<()> {
+        // SAFETY: required for FFI; shouldn't break rust guarantees.
+        match unsafe { libc::inotify_rm_watch(self.file.as_raw_fd(), wd.0) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read all the pending events. Returns an empty list if there aren't any.
+    ///
+    /// The kernel only hands out whole events, and fails the read if the buffer can't hold
+    /// the next one, in which case the buffer is grown and the read retried.
+    pub fn read_events(&self) -> io::Result<Vec<FsEvent>> {
+        let mut events = Vec::new();
+        let mut buf = vec![0u8; 4096];
+
+        loop {
+            match (&self.file).read(&mut buf) {
+                Ok(0) => return Ok(events),
+                Ok(n) => parse(&buf[..n], &mut events)?,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(events),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) && buf.len() < 1 << 16 => {
+                    buf.resize(buf.len() * 2, 0);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl AsRawFd for Watcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl AsFd for Watcher {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+/// Parse the event records read from an `inotify` file descriptor. Each record is a header
+/// followed by a name, padded with zeroes.
+fn parse(mut buf: &[u8], events: &mut Vec<FsEvent>) -> io::Result<()> {
+    while !buf.is_empty() {
+        if buf.len() < HEADER_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated event",
+            ));
+        }
+        // SAFETY: the buffer holds a whole header, which is plain old data, possibly unaligned.
+        let header: libc::inotify_event = unsafe { ptr::read_unaligned(buf.as_ptr() as *const _) };
+        let len = HEADER_LEN + header.len as usize;
+
+        if buf.len() < len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated event",
+            ));
+        }
+        let name = buf[HEADER_LEN..len]
+            .split(|b| *b == 0)
+            .next()
+            .unwrap_or_default();
+
+        events.push(FsEvent {
+            wd: WatchDescriptor(header.wd),
+            mask: header.mask,
+            cookie: header.cookie,
+            name: (!name.is_empty()).then(|| OsStr::from_bytes(name).to_owned()),
+        });
+        buf = &buf[len..];
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{interest, Sources};
+
+    use std::fs;
+    use std::time::Duration;
+
+    /// Encode an event record, as the kernel would.
+    fn record(wd: libc::c_int, mask: u32, name: &str) -> Vec<u8> {
+        let padded = if name.is_empty() {
+            0
+        } else {
+            (name.len() + 16) & !15
+        };
+        let mut buf = Vec::new();
+
+        buf.extend(wd.to_ne_bytes());
+        buf.extend(mask.to_ne_bytes());
+        buf.extend(0u32.to_ne_bytes());
+        buf.extend((padded as u32).to_ne_bytes());
+        buf.extend(name.as_bytes());
+        buf.resize(HEADER_LEN + padded, 0);
+        buf
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut buf = record(1, libc::IN_CREATE, "file.txt");
+        buf.extend(record(-1, libc::IN_Q_OVERFLOW, ""));
+
+        let mut events = Vec::new();
+        parse(&buf, &mut events).unwrap();
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].wd, WatchDescriptor(1));
+        assert_eq!(events[0].name, Some(OsString::from("file.txt")));
+        assert!(!events[0].is_overflow());
+        assert_eq!(events[1].name, None);
+        assert!(events[1].is_overflow());
+
+        let err = parse(&buf[..HEADER_LEN + 4], &mut events).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_watcher() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("popol-watcher-{}", std::process::id()));
+        let path = dir.join("config");
+
+        fs::create_dir_all(&dir)?;
+        fs::write(&path, "a")?;
+
+        let mut sources = Sources::new();
+        let mut events = Vec::new();
+        let watcher = Watcher::new()?;
+        let file = watcher.watch(&path, libc::IN_MODIFY)?;
+        let parent = watcher.watch(&dir, libc::IN_CREATE)?;
+
+        sources.register_fd("watcher", &watcher, interest::READ);
+        assert!(watcher.read_events()?.is_empty());
+
+        fs::write(&path, "b")?;
+        sources.wait_timeout(&mut events, Duration::from_secs(1))?;
+        assert!(events[0].key == "watcher" && events[0].is_readable());
+
+        let changes = watcher.read_events()?;
+        assert!(!changes.is_empty());
+        assert!(changes
+            .iter()
+            .all(|e| e.wd == file && e.mask & libc::IN_MODIFY != 0 && e.name.is_none()));
+
+        fs::write(dir.join("other"), "")?;
+        let changes = watcher.read_events()?;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].wd, parent);
+        assert_eq!(changes[0].name.as_deref(), Some(OsStr::new("other")));
+
+        watcher.unwatch(file)?;
+        let changes = watcher.read_events()?;
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].mask & libc::IN_IGNORED != 0);
+
+        fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+}
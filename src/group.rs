@@ -0,0 +1,215 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::{Sources, Waker};
+
+/// The errors of the members of a [`WakerGroup`] that couldn't be woken.
+#[derive(Debug)]
+pub struct WakeErrors {
+    /// The index of each member that failed, along with its error.
+    pub errors: Vec<(usize, io::Error)>,
+}
+
+impl fmt::Display for WakeErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to wake {} member(s):", self.errors.len())?;
+
+        for (ix, err) in &self.errors {
+            write!(f, " {}: {};", ix, err)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for WakeErrors {}
+
+/// A set of wakers, one for each of many registries, that can all be woken at once.
+///
+/// The group owns its wakers, so it must be kept alive for as long as the registries are
+/// polled. It can be shared between threads.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// use popol::{Sources, Timeout, WakerGroup};
+///
+/// let mut group = WakerGroup::new();
+/// let mut loops = Vec::new();
+///
+/// for _ in 0..4 {
+///     let mut sources = Sources::new();
+///     group.new_member(&mut sources, "waker")?;
+///     loops.push(sources);
+/// }
+/// let group = Arc::new(group);
+/// let handles = loops
+///     .into_iter()
+///     .map(|mut sources| {
+///         thread::spawn(move || {
+///             let mut events = Vec::new();
+///             sources.poll(&mut events, Timeout::from_secs(1)).map(|_| events[0].key)
+///         })
+///     })
+///     .collect::<Vec<_>>();
+///
+/// group.wake_all()?;
+///
+/// for handle in handles {
+///     assert_eq!(handle.join().unwrap()?, "waker");
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct WakerGroup {
+    members: Vec<Waker>,
+}
+
+impl WakerGroup {
+    /// Create an empty group.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a new waker with the given registry, under the given key, and add it to the
+    /// group. Returns the member's index, to wake it with [`WakerGroup::wake_one`].
+    pub fn new_member<K: Eq + Clone, const N: usize>(
+        &mut self,
+        sources: &mut Sources<K, N>,
+        key: K,
+    ) -> io::Result<usize> {
+        self.members.push(Waker::new(sources, key)?);
+
+        Ok(self.members.len() - 1)
+    }
+
+    /// Return the number of members.
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Return whether the group has no members.
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Wake up every member. A member that fails to wake doesn't stop the others from being
+    /// woken: the errors are collected into a [`WakeErrors`], which is the inner error of
+    /// the returned error. Its kind is the kind of the first error.
+    pub fn wake_all(&self) -> io::Result<()> {
+        let errors = self
+            .members
+            .iter()
+            .enumerate()
+            .filter_map(|(ix, waker)| waker.wake().err().map(|err| (ix, err)))
+            .collect::<Vec<_>>();
+
+        match errors.first() {
+            None => Ok(()),
+            Some((_, err)) => Err(io::Error::new(err.kind(), WakeErrors { errors })),
+        }
+    }
+
+    /// Wake up the member with the given index. Fails with [`io::ErrorKind::NotFound`] if
+    /// there is no such member.
+    pub fn wake_one(&self, ix: usize) -> io::Result<()> {
+        match self.members.get(ix) {
+            Some(waker) => waker.wake(),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Timeout;
+
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    #[test]
+    fn test_wake_all() -> io::Result<()> {
+        const LOOPS: usize = 4;
+
+        let mut group = WakerGroup::new();
+        let mut loops = Vec::new();
+
+        for _ in 0..LOOPS {
+            let mut sources = Sources::new();
+            group.new_member(&mut sources, "waker")?;
+            loops.push(sources);
+        }
+        assert_eq!(group.len(), LOOPS);
+
+        let group = Arc::new(group);
+        let barrier = Arc::new(Barrier::new(LOOPS + 1));
+        let handles = loops
+            .into_iter()
+            .map(|mut sources| {
+                let barrier = barrier.clone();
+
+                thread::spawn(move || {
+                    let mut events = Vec::new();
+
+                    barrier.wait();
+                    sources.poll(&mut events, Timeout::from_secs(6))?;
+
+                    Ok::<_, io::Error>(events.iter().map(|e| e.key).collect::<Vec<_>>())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        barrier.wait();
+        group.wake_all()?;
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap()?, vec!["waker"]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_wake_one() -> io::Result<()> {
+        let mut group = WakerGroup::new();
+        let mut a = Sources::new();
+        let mut b = Sources::new();
+        let mut events = Vec::new();
+
+        assert!(group.is_empty());
+        assert_eq!(group.new_member(&mut a, "a")?, 0);
+        assert_eq!(group.new_member(&mut b, "b")?, 1);
+
+        group.wake_one(1)?;
+        assert!(a.poll(&mut events, Timeout::from_millis(1)).is_err());
+        b.poll(&mut events, Timeout::from_millis(1))?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].key, "b");
+
+        let err = group.wake_one(2).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wake_errors() {
+        let errors = WakeErrors {
+            errors: vec![
+                (1, io::ErrorKind::BrokenPipe.into()),
+                (3, io::ErrorKind::Other.into()),
+            ],
+        };
+        let err = io::Error::new(io::ErrorKind::BrokenPipe, errors);
+
+        assert_eq!(
+            err.to_string(),
+            "failed to wake 2 member(s): 1: broken pipe; 3: other error;"
+        );
+        let inner = err.get_ref().unwrap().downcast_ref::<WakeErrors>().unwrap();
+        assert_eq!(inner.errors.len(), 2);
+    }
+}
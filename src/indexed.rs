@@ -0,0 +1,225 @@
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use crate::{Interest, Source, Timeout};
+
+/// Keeps track of sources to poll, keyed by dense integer indices.
+///
+/// Each source is stored in the slot given by its index, which makes looking it up, changing
+/// its interests or unregistering it a constant-time operation. This is a good fit when keys
+/// are already small integers, eg. indices into a slab of connections.
+///
+/// Unused slots below the highest registered index are kept as holes, which `poll` ignores.
+///
+/// Cloning yields a set that polls the same file descriptors, without duplicating them, and
+/// without the readiness events of previous waits.
+#[derive(Debug)]
+pub struct IndexedPoll {
+    /// List of sources passed to `poll`, indexed by key. Holes hold a vacant source.
+    list: Vec<Source>,
+    /// Number of registered sources.
+    len: usize,
+}
+
+impl Clone for IndexedPoll {
+    fn clone(&self) -> Self {
+        let mut list = self.list.clone();
+
+        for source in list.iter_mut() {
+            source.0.revents = 0;
+        }
+        Self {
+            list,
+            len: self.len,
+        }
+    }
+}
+
+impl IndexedPoll {
+    /// Creates a new set of sources to poll.
+    pub fn new() -> Self {
+        Self {
+            list: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Creates a new set of sources to poll, with room for indices up to `cap`.
+    pub fn with_capacity(cap: usize) -> Self {
+        Self {
+            list: Vec::with_capacity(cap),
+            len: 0,
+        }
+    }
+
+    /// Return the number of registered sources.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Return whether the source registry is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Register a new source at the given index, and wait for the specified events. If a
+    /// source was already registered at that index, it is replaced.
+    pub fn register(&mut self, index: usize, fd: &impl AsRawFd, events: impl Into<Interest>) {
+        if index >= self.list.len() {
+            self.list.resize(index + 1, Source::vacant());
+        }
+        if self.list[index].is_vacant() {
+            self.len += 1;
+        }
+        self.list[index] = Source::registered(fd, events.into());
+    }
+
+    /// Unregister the source at the given index. Returns the source, if one was registered.
+    pub fn unregister(&mut self, index: usize) -> Option<Source> {
+        let source = *self.get(index)?;
+
+        self.list[index] = Source::vacant();
+        self.len -= 1;
+
+        while let Some(true) = self.list.last().map(Source::is_vacant) {
+            self.list.pop();
+        }
+        Some(source)
+    }
+
+    /// Add events to poll for on the source at the given index.
+    pub fn add_interest(&mut self, index: usize, events: impl Into<Interest>) -> bool {
+        if let Some(source) = self.get_mut(index) {
+            source.insert(events);
+            return true;
+        }
+        false
+    }
+
+    /// Remove events to poll for on the source at the given index.
+    pub fn remove_interest(&mut self, index: usize, events: impl Into<Interest>) -> bool {
+        if let Some(source) = self.get_mut(index) {
+            source.remove(events);
+            return true;
+        }
+        false
+    }
+
+    /// Get the source at the given index.
+    pub fn get(&self, index: usize) -> Option<&Source> {
+        self.list.get(index).filter(|s| !s.is_vacant())
+    }
+
+    /// Get the source at the given index, mutably.
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Source> {
+        self.list.get_mut(index).filter(|s| !s.is_vacant())
+    }
+
+    /// Wait for readiness events on the registered sources. If no event
+    /// is returned within the given timeout, returns an error of kind [`io::ErrorKind::TimedOut`],
+    /// unless no sources are registered, in which case `Ok(0)` is returned once the timeout
+    /// expires.
+    ///
+    /// Returns the number of sources with events, which can then be read back with
+    /// [`Self::events()`].
+    pub fn poll(&mut self, timeout: impl Into<Timeout>) -> Result<usize, io::Error> {
+        match crate::poll(&mut self.list, timeout.into(), None)? {
+            0 if !self.is_empty() => Err(io::ErrorKind::TimedOut.into()),
+            n => Ok(n),
+        }
+    }
+
+    /// Wait for readiness events on the registered sources, with a timeout.
+    ///
+    /// This is identical to [`Self::poll()`], except that you must specify a timeout with this.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<usize, io::Error> {
+        self.poll(timeout)
+    }
+
+    /// Wait for readiness events on the registered sources, or until the call is interrupted.
+    ///
+    /// This is identical to [`Self::poll()`], except that you cannot specify a timeout with this.
+    pub fn wait(&mut self) -> Result<usize, io::Error> {
+        self.poll(Timeout::Never)
+    }
+
+    /// Iterate over the events returned by the last wait, along with the index of their
+    /// source.
+    pub fn events(&self) -> impl Iterator<Item = (usize, &Source)> + '_ {
+        self.list
+            .iter()
+            .enumerate()
+            .filter(|(_, source)| source.0.revents != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interest;
+
+    use std::io::prelude::*;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_sparse() -> io::Result<()> {
+        let (mut writer0, reader0) = UnixStream::pair()?;
+        let (mut writer1, reader1) = UnixStream::pair()?;
+        let mut sources = IndexedPoll::new();
+
+        sources.register(3, &reader0, interest::READ);
+        sources.register(100, &reader1, interest::READ);
+
+        assert_eq!(sources.len(), 2);
+        assert!(sources.get(0).is_none() && sources.get(99).is_none());
+        assert!(!sources.add_interest(42, interest::WRITE));
+
+        // Holes are ignored by `poll`.
+        let err = sources.poll(Timeout::from_millis(1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        writer0.write_all(&[1])?;
+        writer1.write_all(&[1])?;
+
+        assert_eq!(sources.poll(Timeout::from_millis(1))?, 2);
+        assert_eq!(
+            sources.events().map(|(ix, _)| ix).collect::<Vec<_>>(),
+            vec![3, 100]
+        );
+        assert!(sources.events().all(|(_, s)| s.is_readable()));
+        assert_eq!(sources.clone().events().count(), 0);
+
+        // Unregistering the last index shrinks the list back down.
+        sources.unregister(100);
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources.list.len(), 4);
+
+        sources.remove_interest(3, interest::READ);
+        let err = sources.poll(Timeout::from_millis(1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        sources.unregister(3);
+        sources.unregister(3);
+        assert!(sources.is_empty() && sources.list.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace() -> io::Result<()> {
+        let (mut writer, reader) = UnixStream::pair()?;
+        let (_idle, idle) = UnixStream::pair()?;
+        let mut sources = IndexedPoll::new();
+
+        sources.register(1, &reader, interest::READ);
+        sources.register(1, &idle, interest::READ);
+        assert_eq!(sources.len(), 1);
+
+        writer.write_all(&[1])?;
+        let err = sources.poll(Timeout::from_millis(1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,444 @@
+//! Helpers for non-blocking sockets.
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::{self, UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::{interest, Sources};
+
+/// Accept all the pending connections on a TCP listener, eg. once it is readable. Returns an
+/// empty list if there weren't any.
+///
+/// The listener must be in non-blocking mode, or this blocks once the pending connections
+/// are accepted. Accepted streams are non-blocking, and closed on `exec`, ready to be
+/// registered. Connections aborted before they are accepted are skipped.
+///
+/// # Examples
+///
+/// ```
+/// use std::net::{TcpListener, TcpStream};
+/// use popol::net;
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// listener.set_nonblocking(true)?;
+///
+/// let _client = TcpStream::connect(listener.local_addr()?)?;
+/// let accepted = net::accept_pending(&listener)?;
+///
+/// assert_eq!(accepted.len(), 1);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn accept_pending(listener: &TcpListener) -> io::Result<Vec<(TcpStream, SocketAddr)>> {
+    accept_all(|| accept(listener))
+}
+
+/// Accept all the pending connections on a Unix listener. See [`accept_pending`].
+pub fn accept_pending_unix(
+    listener: &UnixListener,
+) -> io::Result<Vec<(UnixStream, net::SocketAddr)>> {
+    accept_all(|| {
+        let (stream, addr) = listener.accept()?;
+        stream.set_nonblocking(true)?;
+
+        Ok((stream, addr))
+    })
+}
+
+/// Accept connections until there are no more pending.
+fn accept_all<T>(mut accept: impl FnMut() -> io::Result<T>) -> io::Result<Vec<T>> {
+    let mut accepted = Vec::new();
+
+    loop {
+        match accept() {
+            Ok(conn) => accepted.push(conn),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(accepted),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Accept a connection, and make it non-blocking and close-on-exec in the same call.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn accept(listener: &TcpListener) -> io::Result<(TcpStream, SocketAddr)> {
+    // SAFETY: an all-zero `sockaddr_storage` is valid.
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of_val(&storage) as libc::socklen_t;
+
+    // SAFETY: required for FFI; shouldn't break rust guarantees.
+    match unsafe {
+        libc::accept4(
+            listener.as_raw_fd(),
+            &mut storage as *mut _ as *mut libc::sockaddr,
+            &mut len,
+            libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+        )
+    } {
+        -1 => Err(io::Error::last_os_error()),
+        // SAFETY: the file descriptor was just created, and is owned by nothing else.
+        fd => Ok((
+            unsafe { TcpStream::from_raw_fd(fd) },
+            socket_addr(&storage)?,
+        )),
+    }
+}
+
+/// Accept a connection, and make it non-blocking. The standard library makes it
+/// close-on-exec.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn accept(listener: &TcpListener) -> io::Result<(TcpStream, SocketAddr)> {
+    let (stream, addr) = listener.accept()?;
+    stream.set_nonblocking(true)?;
+
+    Ok((stream, addr))
+}
+
+/// Connect to a TCP address, giving up once the timeout expires, with an error of kind
+/// [`io::ErrorKind::TimedOut`].
+///
+/// The connection is initiated without blocking, and waited on until the socket is writable.
+/// Failures to connect are reported with the socket's error, eg. of kind
+/// [`io::ErrorKind::ConnectionRefused`]. The returned stream is non-blocking, and closed on
+/// `exec`, ready to be registered.
+///
+/// # Examples
+///
+/// ```
+/// use std::net::TcpListener;
+/// use std::time::Duration;
+/// use popol::net;
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// let stream = net::connect_timeout(&listener.local_addr()?, Duration::from_secs(1))?;
+///
+/// assert_eq!(stream.peer_addr()?, listener.local_addr()?);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn connect_timeout(addr: &SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+    let (domain, storage, len) = sockaddr(addr);
+
+    connect(domain, &storage, len, timeout).map(TcpStream::from)
+}
+
+/// Fetch and clear the pending error of a socket, eg. once it is reported with
+/// [`crate::Source::is_error`]. Returns `None` if there is no pending error.
+///
+/// The error is cleared as it is read, so it can only be fetched once.
+pub fn take_socket_error(fd: &impl AsRawFd) -> io::Result<Option<io::Error>> {
+    let mut error: libc::c_int = 0;
+    let mut len = mem::size_of_val(&error) as libc::socklen_t;
+
+    // SAFETY: required for FFI; shouldn't break rust guarantees.
+    match unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ERROR,
+            &mut error as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    } {
+        -1 => Err(io::Error::last_os_error()),
+        _ if error != 0 => Ok(Some(io::Error::from_raw_os_error(error))),
+        _ => Ok(None),
+    }
+}
+
+/// Convert an IPv4 or IPv6 socket address for the kernel. Returns its address family, and
+/// its length.
+fn sockaddr(addr: &SocketAddr) -> (libc::c_int, libc::sockaddr_storage, usize) {
+    // SAFETY: an all-zero `sockaddr_storage` is valid.
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let (domain, len) = match addr {
+        SocketAddr::V4(addr) => {
+            // SAFETY: a `sockaddr_in` fits in a `sockaddr_storage`.
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+
+            (libc::AF_INET, mem::size_of::<libc::sockaddr_in>())
+        }
+        SocketAddr::V6(addr) => {
+            // SAFETY: a `sockaddr_in6` fits in a `sockaddr_storage`.
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_scope_id = addr.scope_id();
+
+            (libc::AF_INET6, mem::size_of::<libc::sockaddr_in6>())
+        }
+    };
+    (domain, storage, len)
+}
+
+/// Connect to a Unix socket, giving up once the timeout expires. See [`connect_timeout`].
+///
+/// Connecting to a listener whose backlog is full fails with an error of kind
+/// [`io::ErrorKind::WouldBlock`] on Linux, rather than waiting.
+pub fn connect_timeout_unix(path: impl AsRef<Path>, timeout: Duration) -> io::Result<UnixStream> {
+    let path = path.as_ref().as_os_str().as_bytes();
+    // SAFETY: an all-zero `sockaddr_storage` is valid.
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    // SAFETY: a `sockaddr_un` fits in a `sockaddr_storage`.
+    let sun = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_un) };
+
+    // The path must leave room for a terminating zero.
+    if path.len() >= sun.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "socket path is too long",
+        ));
+    }
+    sun.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dst, src) in sun.sun_path.iter_mut().zip(path) {
+        *dst = *src as libc::c_char;
+    }
+    let len = mem::size_of::<libc::sockaddr_un>() - sun.sun_path.len() + path.len() + 1;
+
+    connect(libc::AF_UNIX, &storage, len, timeout).map(UnixStream::from)
+}
+
+/// Create a non-blocking, close-on-exec stream socket, and connect it to the given address.
+fn connect(
+    domain: libc::c_int,
+    addr: &libc::sockaddr_storage,
+    len: usize,
+    timeout: Duration,
+) -> io::Result<OwnedFd> {
+    let fd = socket(domain)?;
+
+    // SAFETY: required for FFI; shouldn't break rust guarantees.
+    if unsafe {
+        libc::connect(
+            fd.as_raw_fd(),
+            addr as *const _ as *const libc::sockaddr,
+            len as libc::socklen_t,
+        )
+    } == 0
+    {
+        return Ok(fd);
+    }
+    match io::Error::last_os_error() {
+        // An interrupted connect carries on in the background, like one in progress.
+        e if matches!(e.raw_os_error(), Some(libc::EINPROGRESS | libc::EINTR)) => {}
+        e => return Err(e),
+    }
+    let mut sources = Sources::<()>::new();
+    let mut events = Vec::new();
+
+    sources.register((), &fd, interest::WRITE);
+    sources.poll_retry(&mut events, timeout)?;
+
+    match take_socket_error(&fd)? {
+        Some(err) => Err(err),
+        None => Ok(fd),
+    }
+}
+
+/// Create a non-blocking, close-on-exec stream socket.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn socket(domain: libc::c_int) -> io::Result<OwnedFd> {
+    let ty = libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+
+    // SAFETY: required for FFI; shouldn't break rust guarantees.
+    match unsafe { libc::socket(domain, ty, 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        // SAFETY: the file descriptor was just created, and is owned by nothing else.
+        fd => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+    }
+}
+
+/// Create a non-blocking, close-on-exec stream socket.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn socket(domain: libc::c_int) -> io::Result<OwnedFd> {
+    // SAFETY: required for FFI; shouldn't break rust guarantees.
+    let fd = match unsafe { libc::socket(domain, libc::SOCK_STREAM, 0) } {
+        -1 => return Err(io::Error::last_os_error()),
+        // SAFETY: the file descriptor was just created, and is owned by nothing else.
+        fd => unsafe { OwnedFd::from_raw_fd(fd) },
+    };
+    crate::prepare(&fd)?;
+
+    Ok(fd)
+}
+
+/// Convert an IPv4 or IPv6 socket address filled in by the kernel.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn socket_addr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // SAFETY: the family says the storage holds a `sockaddr_in`, which fits in it.
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            // SAFETY: the family says the storage holds a `sockaddr_in6`, which fits in it.
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected address family",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    /// Whether the file descriptor is non-blocking and close-on-exec.
+    fn is_nonblocking_cloexec(fd: &impl AsRawFd) -> bool {
+        let fd = fd.as_raw_fd();
+
+        unsafe {
+            libc::fcntl(fd, libc::F_GETFL) & libc::O_NONBLOCK != 0
+                && libc::fcntl(fd, libc::F_GETFD) & libc::FD_CLOEXEC != 0
+        }
+    }
+
+    #[test]
+    fn test_accept_pending() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+
+        assert!(accept_pending(&listener)?.is_empty());
+
+        let clients = (0..3)
+            .map(|_| TcpStream::connect(listener.local_addr()?))
+            .collect::<io::Result<Vec<_>>>()?;
+        let accepted = accept_pending(&listener)?;
+
+        assert_eq!(accepted.len(), 3);
+        for (stream, addr) in &accepted {
+            assert!(is_nonblocking_cloexec(stream));
+            assert!(clients.iter().any(|c| c.local_addr().ok() == Some(*addr)));
+            assert_eq!(stream.peer_addr()?, *addr);
+        }
+        assert!(accept_pending(&listener)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_connect_timeout() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+
+        let start = Instant::now();
+        let stream = connect_timeout(&addr, Duration::from_secs(1))?;
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(is_nonblocking_cloexec(&stream));
+        assert_eq!(stream.peer_addr()?, addr);
+
+        // Once the accept queue is full, connection attempts are ignored, and time out.
+        assert_eq!(unsafe { libc::listen(listener.as_raw_fd(), 0) }, 0);
+        let mut streams = Vec::new();
+        let err = loop {
+            match connect_timeout(&addr, Duration::from_millis(50)) {
+                Ok(stream) if streams.len() < 8 => streams.push(stream),
+                Ok(_) => panic!("the accept queue never filled up"),
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        drop(listener);
+        let err = connect_timeout(&addr, Duration::from_secs(1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_take_socket_error() -> io::Result<()> {
+        let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let (domain, storage, len) = sockaddr(&addr);
+        let fd = socket(domain)?;
+
+        assert!(take_socket_error(&fd)?.is_none());
+        unsafe {
+            libc::connect(
+                fd.as_raw_fd(),
+                &storage as *const _ as *const libc::sockaddr,
+                len as libc::socklen_t,
+            )
+        };
+        let mut sources = Sources::new();
+        let mut events = Vec::new();
+
+        sources.register("socket", &fd, interest::WRITE);
+        sources.poll(&mut events, Duration::from_secs(1))?;
+        assert!(events[0].is_error());
+
+        let err = take_socket_error(&fd)?.unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(take_socket_error(&fd)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_connect_timeout_unix() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("popol-connect-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+
+        let stream = connect_timeout_unix(&path, Duration::from_secs(1))?;
+        assert!(is_nonblocking_cloexec(&stream));
+        assert!(listener.accept().is_ok());
+
+        let err = connect_timeout_unix("a".repeat(4096), Duration::ZERO);
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        drop(listener);
+        std::fs::remove_file(&path)?;
+        let err = connect_timeout_unix(&path, Duration::from_secs(1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_accept_pending_unix() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("popol-accept-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+
+        let _clients = (0..3)
+            .map(|_| UnixStream::connect(&path))
+            .collect::<io::Result<Vec<_>>>()?;
+        let accepted = accept_pending_unix(&listener)?;
+
+        assert_eq!(accepted.len(), 3);
+        assert!(accepted.iter().all(|(s, _)| is_nonblocking_cloexec(s)));
+        assert!(accept_pending_unix(&listener)?.is_empty());
+
+        std::fs::remove_file(&path)
+    }
+}
@@ -0,0 +1,161 @@
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use crate::{Compaction, Event, Interest, Source, Sources, Timeout};
+
+/// Keeps track of sources to poll, and owns the I/O objects they were registered with.
+///
+/// Unlike [`Sources`], which only stores raw file descriptors, this keeps each object alive
+/// for as long as it is registered, so a source can't be closed while still being polled.
+/// Unregistering a source hands its object back.
+#[derive(Debug)]
+pub struct OwnedSources<K, T> {
+    /// Sources to poll. They are never compacted, so that slots stay valid.
+    sources: Sources<K>,
+    /// The object registered in each slot.
+    objects: Vec<Option<T>>,
+}
+
+impl<K: Clone + PartialEq, T: AsRawFd> OwnedSources<K, T> {
+    /// Creates a new set of sources to poll.
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    /// Creates a new set of sources to poll, with the given capacity.
+    pub fn with_capacity(cap: usize) -> Self {
+        let mut sources = Sources::with_capacity(cap);
+        sources.set_compaction(Compaction::Never);
+
+        Self {
+            sources,
+            objects: Vec::with_capacity(cap),
+        }
+    }
+
+    /// Return the number of registered sources.
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    /// Return whether the source registry is empty.
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Register an object, with the given key, and wait for the specified events.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if a source is already registered with this key.
+    /// See [`Sources::register`].
+    pub fn register(&mut self, key: K, object: T, events: impl Into<Interest>) {
+        debug_assert!(!self.sources.contains(&key), "registering duplicate key");
+
+        let ix = self
+            .sources
+            .insert(key, Source::registered(&object, events.into()));
+
+        if ix >= self.objects.len() {
+            self.objects.resize_with(ix + 1, || None);
+        }
+        self.objects[ix] = Some(object);
+    }
+
+    /// Unregister a source, given its key. Returns the object it was registered with, which
+    /// is closed once dropped.
+    pub fn unregister(&mut self, key: &K) -> Option<T> {
+        let ix = self.sources.find(key)?;
+        self.sources.remove(ix);
+
+        self.objects[ix].take()
+    }
+
+    /// Add events to poll for on a source identified by its key.
+    pub fn add_interest(&mut self, key: &K, events: impl Into<Interest>) -> bool {
+        self.sources.add_interest(key, events)
+    }
+
+    /// Remove events to poll for on a source identified by its key.
+    pub fn remove_interest(&mut self, key: &K, events: impl Into<Interest>) -> bool {
+        self.sources.remove_interest(key, events)
+    }
+
+    /// Get the object registered with the given key.
+    pub fn get(&self, key: &K) -> Option<&T> {
+        let ix = self.sources.find(key)?;
+        self.objects[ix].as_ref()
+    }
+
+    /// Get the object registered with the given key, mutably, eg. to read from it.
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut T> {
+        let ix = self.sources.find(key)?;
+        self.objects[ix].as_mut()
+    }
+
+    /// Wait for readiness events on the registered sources. See [`Sources::poll`].
+    pub fn poll(
+        &mut self,
+        events: &mut Vec<Event<K>>,
+        timeout: impl Into<Timeout>,
+    ) -> Result<usize, io::Error> {
+        self.sources.poll(events, timeout)
+    }
+
+    /// Wait for readiness events on the registered sources, with a timeout.
+    /// See [`Sources::wait_timeout`].
+    pub fn wait_timeout(
+        &mut self,
+        events: &mut Vec<Event<K>>,
+        timeout: Duration,
+    ) -> Result<usize, io::Error> {
+        self.poll(events, timeout)
+    }
+
+    /// Wait for readiness events on the registered sources, or until the call is interrupted.
+    /// See [`Sources::wait`].
+    pub fn wait(&mut self, events: &mut Vec<Event<K>>) -> Result<usize, io::Error> {
+        self.poll(events, Timeout::Never)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interest;
+
+    use std::io::prelude::*;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_owned() -> io::Result<()> {
+        let (mut writer0, reader0) = UnixStream::pair()?;
+        let (mut writer1, reader1) = UnixStream::pair()?;
+        let mut sources = OwnedSources::new();
+        let mut events = Vec::new();
+        let mut buf = [0; 1];
+
+        sources.register("reader0", reader0, interest::READ);
+        sources.register("reader1", reader1, interest::READ);
+        writer1.write_all(&[7])?;
+
+        sources.poll(&mut events, Timeout::from_secs(1))?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].key, "reader1");
+
+        sources.get_mut(&"reader1").unwrap().read_exact(&mut buf)?;
+        assert_eq!(buf, [7]);
+
+        // Dropping the unregistered object closes it.
+        drop(sources.unregister(&"reader0").unwrap());
+        assert!(sources.get(&"reader0").is_none());
+        assert_eq!(
+            writer0.write_all(&[1]).unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+        assert_eq!(sources.len(), 1);
+
+        Ok(())
+    }
+}
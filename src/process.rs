@@ -0,0 +1,155 @@
+//! Child process exits as readiness events, backed by `pidfd` on Linux 5.3 and later.
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
+use std::process::{Child, ExitStatus};
+
+/// A file descriptor referring to a process, which becomes readable when the process exits.
+///
+/// A process that exited before its `pidfd` was opened, but wasn't reaped yet, is readable
+/// right away, so the exit can't be missed. Opening fails with an error of raw OS code
+/// `ESRCH` if the process was reaped already. The file descriptor is closed on `exec`.
+///
+/// # Examples
+///
+/// ```
+/// use std::process::Command;
+/// use popol::{interest, process::PidFd, Sources, Timeout};
+///
+/// let mut child = Command::new("true").spawn()?;
+/// let pidfd = PidFd::from_child(&child)?;
+/// let mut sources = Sources::new();
+/// let mut events = Vec::new();
+///
+/// sources.register_fd("child", &pidfd, interest::READ);
+/// sources.poll(&mut events, Timeout::Never)?;
+///
+/// assert!(pidfd.wait_exit_status(&mut child)?.success());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct PidFd {
+    file: File,
+    pid: libc::pid_t,
+}
+
+impl PidFd {
+    /// Open a `pidfd` for a child process.
+    pub fn from_child(child: &Child) -> io::Result<Self> {
+        Self::from_pid(child.id() as libc::pid_t)
+    }
+
+    /// Open a `pidfd` for the process with the given id.
+    ///
+    /// Unlike with [`Self::from_child`], nothing stops the process from being reaped, and its
+    /// id reused, before this is called.
+    pub fn from_pid(pid: libc::pid_t) -> io::Result<Self> {
+        // SAFETY: required for FFI; shouldn't break rust guarantees.
+        match unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(Self {
+                // SAFETY: the file descriptor was just created, and is owned by nothing else.
+                file: unsafe { File::from_raw_fd(fd as RawFd) },
+                pid,
+            }),
+        }
+    }
+
+    /// The id of the process.
+    pub fn pid(&self) -> libc::pid_t {
+        self.pid
+    }
+
+    /// Reap the child process, and return its exit status. Call this once the `pidfd` is
+    /// readable: it blocks until the child exits otherwise.
+    ///
+    /// Fails with an error of kind [`io::ErrorKind::InvalidInput`] if the child isn't the
+    /// process of this `pidfd`.
+    pub fn wait_exit_status(&self, child: &mut Child) -> io::Result<ExitStatus> {
+        if child.id() as libc::pid_t != self.pid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "child doesn't match the pidfd",
+            ));
+        }
+        child.wait()
+    }
+}
+
+impl AsRawFd for PidFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl AsFd for PidFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{interest, Sources, Timeout};
+
+    use std::process::Command;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_pidfd() -> io::Result<()> {
+        let mut sources = Sources::new();
+        let mut events = Vec::new();
+        let mut child = Command::new("sh")
+            .args(["-c", "sleep 0.05; exit 3"])
+            .spawn()?;
+        let pidfd = PidFd::from_child(&child)?;
+
+        sources.register_fd("child", &pidfd, interest::READ);
+        assert!(!sources.try_wait(&mut events)?);
+
+        let start = Instant::now();
+        sources.poll(&mut events, Timeout::from_secs(5))?;
+        assert!(start.elapsed() >= Duration::from_millis(10));
+
+        assert_eq!(events.len(), 1);
+        assert!(events[0].key == "child" && events[0].is_readable());
+        assert_eq!(pidfd.wait_exit_status(&mut child)?.code(), Some(3));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pidfd_exited() -> io::Result<()> {
+        let mut sources = Sources::new();
+        let mut events = Vec::new();
+        let mut child = Command::new("true").spawn()?;
+        let mut other = Command::new("true").spawn()?;
+
+        // Wait for the child to exit before its pidfd is opened, without reaping it.
+        let mut info = unsafe { std::mem::zeroed() };
+        let flags = libc::WEXITED | libc::WNOWAIT;
+        assert_eq!(
+            unsafe { libc::waitid(libc::P_PID, child.id(), &mut info, flags) },
+            0
+        );
+
+        let pidfd = PidFd::from_child(&child)?;
+        sources.register_fd("child", &pidfd, interest::READ);
+        sources.poll(&mut events, Timeout::ZERO)?;
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            pidfd.wait_exit_status(&mut other).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert!(pidfd.wait_exit_status(&mut child)?.success());
+        assert!(other.wait()?.success());
+
+        // Once reaped, a process can't be referred to anymore.
+        let err = PidFd::from_child(&child).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ESRCH));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+use std::io;
+use std::io::prelude::*;
+
+use crate::{interest, Event, Sources};
+
+/// The outcome of handling an event with a [`Pump`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PumpStatus {
+    /// Bytes read from the source.
+    pub read: usize,
+    /// Bytes written to the destination.
+    pub written: usize,
+    /// Whether the source reached its end. There may still be bytes buffered.
+    pub eof: bool,
+    /// Whether the source reached its end, and every byte read was written.
+    pub done: bool,
+}
+
+/// Relays bytes from one registered source to another, through a bounded buffer.
+///
+/// The pump sets interests on both sources as it goes: it stops reading from the source while
+/// its buffer is full, and only waits for the destination to be writable while bytes are
+/// buffered. Both sources should be non-blocking, and registered with the keys given to
+/// [`Pump::new`]. Their initial interests don't matter, since they are set on the first
+/// event. A source that hung up keeps being reported until it is unregistered, which can be
+/// done once its end is reached.
+#[derive(Debug)]
+pub struct Pump<K> {
+    src: K,
+    dst: K,
+    buf: Box<[u8]>,
+    /// Start of the buffered bytes.
+    start: usize,
+    /// End of the buffered bytes.
+    end: usize,
+    eof: bool,
+}
+
+impl<K: Clone + PartialEq> Pump<K> {
+    /// Create a pump from the source with the first key to the one with the second, with a
+    /// buffer of the given capacity.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the capacity is zero.
+    pub fn new(src: K, dst: K, capacity: usize) -> Self {
+        assert!(capacity > 0, "pump capacity must be greater than zero");
+
+        Self {
+            src,
+            dst,
+            buf: vec![0; capacity].into_boxed_slice(),
+            start: 0,
+            end: 0,
+            eof: false,
+        }
+    }
+
+    /// Number of bytes read from the source, but not yet written to the destination.
+    pub fn buffered(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Handle an event on either source, reading and writing as much as possible, and update
+    /// their interests. Events for other keys are ignored.
+    pub fn handle<const N: usize>(
+        &mut self,
+        sources: &mut Sources<K, N>,
+        event: &Event<K>,
+        src: &mut impl Read,
+        dst: &mut impl Write,
+    ) -> io::Result<PumpStatus> {
+        let mut status = PumpStatus::default();
+
+        if event.key == self.src && (event.is_readable() || event.is_hangup()) {
+            status.read = self.fill(src)?;
+        }
+        // Writing right after reading saves a wait, since the destination is likely writable.
+        if event.key == self.dst || status.read > 0 {
+            status.written = self.flush(dst)?;
+        }
+        status.eof = self.eof;
+        status.done = self.eof && self.buffered() == 0;
+
+        if self.eof || self.end == self.buf.len() {
+            sources.remove_interest(&self.src, interest::READ);
+        } else {
+            sources.add_interest(&self.src, interest::READ);
+        }
+        if self.buffered() > 0 {
+            sources.add_interest(&self.dst, interest::WRITE);
+        } else {
+            sources.remove_interest(&self.dst, interest::WRITE);
+        }
+        Ok(status)
+    }
+
+    /// Read into the buffer until it is full, or the source would block.
+    fn fill(&mut self, src: &mut impl Read) -> io::Result<usize> {
+        let mut read = 0;
+
+        if self.start > 0 {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+        while !self.eof && self.end < self.buf.len() {
+            match src.read(&mut self.buf[self.end..]) {
+                Ok(0) => self.eof = true,
+                Ok(n) => {
+                    self.end += n;
+                    read += n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(read)
+    }
+
+    /// Write from the buffer until it is empty, or the destination would block.
+    fn flush(&mut self, dst: &mut impl Write) -> io::Result<usize> {
+        let mut written = 0;
+
+        while self.start < self.end {
+            match dst.write(&self.buf[self.start..self.end]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.start += n;
+                    written += n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        }
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Timeout;
+
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    #[test]
+    fn test_pump() -> io::Result<()> {
+        const LEN: usize = 4 * 1024 * 1024;
+
+        let data = (0..LEN).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let (mut input, mut src) = UnixStream::pair()?;
+        let (mut dst, mut output) = UnixStream::pair()?;
+
+        src.set_nonblocking(true)?;
+        dst.set_nonblocking(true)?;
+
+        let writer = thread::spawn({
+            let data = data.clone();
+            move || input.write_all(&data)
+        });
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            output.read_to_end(&mut received).map(|_| received)
+        });
+
+        let mut sources = Sources::new();
+        let mut events = Vec::new();
+        let mut pump = Pump::new("src", "dst", 1024);
+        let mut total = PumpStatus::default();
+
+        sources.register("src", &src, interest::READ);
+        sources.register("dst", &dst, interest::NONE);
+
+        while !total.done {
+            events.clear();
+            sources.poll(&mut events, Timeout::from_secs(6))?;
+
+            for event in &events {
+                let status = pump.handle(&mut sources, event, &mut src, &mut dst)?;
+
+                assert!(pump.buffered() <= 1024);
+                total.read += status.read;
+                total.written += status.written;
+                total.done = status.done;
+            }
+        }
+        assert_eq!(total.read, LEN);
+        assert_eq!(total.written, LEN);
+
+        // Once idle, the pump doesn't wait on either source anymore.
+        assert_eq!(sources.interests(&"src"), Some(interest::NONE));
+        assert_eq!(sources.interests(&"dst"), Some(interest::NONE));
+
+        drop(dst);
+        writer.join().unwrap()?;
+        assert!(reader.join().unwrap()? == data);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::{Event, Interest, InterestUpdater, Sources, Timeout};
+
+/// A registry of sources that can be shared between threads.
+///
+/// Sources can be registered, unregistered and have their interests changed from any thread,
+/// including while another thread waits. Changes are queued, and interrupt the wait in
+/// progress, which applies them and waits again, so that they take effect promptly.
+///
+/// Cloning a `SharedPoll` yields a handle to the same registry.
+#[derive(Debug)]
+pub struct SharedPoll<K> {
+    shared: Arc<Shared<K>>,
+}
+
+#[derive(Debug)]
+struct Shared<K> {
+    /// The registry. Only locked while waiting.
+    sources: Mutex<Sources<K>>,
+    /// Queues changes to the registry, and interrupts waits.
+    updater: InterestUpdater<K>,
+    /// The key under which the updater is registered.
+    key: K,
+}
+
+impl<K> Clone for SharedPoll<K> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<K: Clone + PartialEq> SharedPoll<K> {
+    /// Creates a new, empty registry. Its built-in waker is registered with the given key,
+    /// which shouldn't be used for any other source.
+    pub fn new(key: K) -> io::Result<Self> {
+        let mut sources = Sources::new();
+        let updater = sources.updater(key.clone())?;
+
+        Ok(Self {
+            shared: Arc::new(Shared {
+                sources: Mutex::new(sources),
+                updater,
+                key,
+            }),
+        })
+    }
+
+    /// Register a new source, with the given key, and wait for the specified events.
+    ///
+    /// The file descriptor must stay open until the source is unregistered.
+    pub fn register(
+        &self,
+        key: K,
+        fd: &impl AsRawFd,
+        events: impl Into<Interest>,
+    ) -> io::Result<()> {
+        self.shared.updater.register(key, fd, events)
+    }
+
+    /// Unregister a source, given its key.
+    pub fn unregister(&self, key: K) -> io::Result<()> {
+        self.shared.updater.unregister(key)
+    }
+
+    /// Add events to poll for on a source identified by its key.
+    pub fn add_interest(&self, key: K, events: impl Into<Interest>) -> io::Result<()> {
+        self.shared.updater.add_interest(key, events)
+    }
+
+    /// Remove events to poll for on a source identified by its key.
+    pub fn remove_interest(&self, key: K, events: impl Into<Interest>) -> io::Result<()> {
+        self.shared.updater.remove_interest(key, events)
+    }
+
+    /// Wait for readiness events on the registered sources. See [`Sources::poll`].
+    ///
+    /// Changes made while waiting are applied, and the wait resumes until the timeout
+    /// expires. Events of the built-in waker are never returned. Only one thread can wait at
+    /// a time: others block until it returns.
+    pub fn poll(
+        &self,
+        events: &mut Vec<Event<K>>,
+        timeout: impl Into<Timeout>,
+    ) -> Result<usize, io::Error> {
+        let deadline = match timeout.into() {
+            Timeout::After(duration) => Some(Instant::now() + duration),
+            Timeout::Never => None,
+        };
+        let mut sources = self
+            .shared
+            .sources
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        loop {
+            let timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            let len = events.len();
+
+            sources.poll(events, timeout)?;
+
+            if let Some(ix) = events[len..].iter().position(|e| e.key == self.shared.key) {
+                events.remove(len + ix);
+            }
+            if events.len() > len {
+                return Ok(events.len() - len);
+            }
+        }
+    }
+
+    /// Wait for readiness events on the registered sources, or until the timeout expires.
+    /// See [`Self::poll`].
+    pub fn wait_timeout(
+        &self,
+        events: &mut Vec<Event<K>>,
+        timeout: Duration,
+    ) -> Result<usize, io::Error> {
+        self.poll(events, timeout)
+    }
+
+    /// Wait for readiness events on the registered sources, or until the call is interrupted.
+    /// See [`Self::poll`].
+    pub fn wait(&self, events: &mut Vec<Event<K>>) -> Result<usize, io::Error> {
+        self.poll(events, Timeout::Never)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interest;
+
+    use std::io::prelude::*;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    #[test]
+    fn test_register_while_waiting() -> io::Result<()> {
+        let (mut writer0, reader0) = UnixStream::pair()?;
+        let (mut writer1, reader1) = UnixStream::pair()?;
+        let poll = SharedPoll::new("waker")?;
+
+        writer0.write_all(&[1])?;
+
+        let waiter = thread::spawn({
+            let poll = poll.clone();
+            move || {
+                let mut events = Vec::new();
+                poll.poll(&mut events, Timeout::from_secs(6))
+                    .map(|_| events)
+            }
+        });
+        thread::sleep(Duration::from_millis(50));
+        poll.register("reader0", &reader0, interest::READ)?;
+
+        let events = waiter.join().unwrap()?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].key, "reader0");
+
+        // Unregistering while waiting takes effect too.
+        (&reader0).read_exact(&mut [0])?;
+        let waiter = thread::spawn({
+            let poll = poll.clone();
+            move || {
+                let mut events = Vec::new();
+                poll.poll(&mut events, Timeout::from_secs(6))
+                    .map(|_| events)
+            }
+        });
+        thread::sleep(Duration::from_millis(50));
+        poll.unregister("reader0")?;
+        poll.register("reader1", &reader1, interest::READ)?;
+        writer1.write_all(&[1])?;
+
+        let events = waiter.join().unwrap()?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].key, "reader1");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_timeout() -> io::Result<()> {
+        let (_writer, reader) = UnixStream::pair()?;
+        let poll = SharedPoll::new("waker")?;
+        let mut events = Vec::new();
+
+        poll.register("reader", &reader, interest::READ)?;
+
+        // Wakes caused by changes don't cut the wait short.
+        let handle = thread::spawn({
+            let poll = poll.clone();
+            move || {
+                thread::sleep(Duration::from_millis(20));
+                poll.add_interest("reader", interest::READ).unwrap();
+            }
+        });
+        let start = Instant::now();
+        let err = poll
+            .poll(&mut events, Timeout::from_millis(100))
+            .unwrap_err();
+        handle.join().unwrap();
+
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(events.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+//! Signal handling through the poller, backed by `signalfd` on Linux.
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
+
+/// A signal read from a [`SignalFd`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignalInfo {
+    /// The signal number, eg. `libc::SIGTERM`.
+    pub signal: libc::c_int,
+    /// The process id of the sender.
+    pub pid: libc::pid_t,
+    /// The real user id of the sender.
+    pub uid: libc::uid_t,
+}
+
+/// A file descriptor that becomes readable when one of its signals is pending.
+///
+/// Creating one blocks its signals with `sigprocmask`, so that they are queued for the signal
+/// file descriptor instead of being handled. The mask only applies to the calling thread, and
+/// the threads it spawns after, so create it on the main thread before spawning any others.
+/// The signals stay blocked when it is dropped. It is non-blocking, and closed on `exec`.
+///
+/// # Examples
+///
+/// ```
+/// use popol::{interest, signals::SignalFd, Sources, Timeout};
+///
+/// let mut sources = Sources::new();
+/// let mut events = Vec::new();
+/// let signals = SignalFd::new(&[libc::SIGUSR2])?;
+///
+/// sources.register_fd("signals", &signals, interest::READ);
+/// unsafe { libc::raise(libc::SIGUSR2) };
+/// sources.poll(&mut events, Timeout::Never)?;
+///
+/// let info = signals.read_signal()?.unwrap();
+/// assert_eq!(info.signal, libc::SIGUSR2);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct SignalFd {
+    file: File,
+    signals: Vec<libc::c_int>,
+}
+
+impl SignalFd {
+    /// Block the given signals, and create a signal file descriptor that reads them.
+    pub fn new(signals: &[libc::c_int]) -> io::Result<Self> {
+        let mask = sigset(signals)?;
+
+        sigprocmask(libc::SIG_BLOCK, &mask)?;
+
+        // SAFETY: required for FFI; shouldn't break rust guarantees.
+        match unsafe { libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC) } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(Self {
+                // SAFETY: the file descriptor was just created, and is owned by nothing else.
+                file: unsafe { File::from_raw_fd(fd) },
+                signals: signals.to_vec(),
+            }),
+        }
+    }
+
+    /// The signals read by this file descriptor.
+    pub fn signals(&self) -> &[libc::c_int] {
+        &self.signals
+    }
+
+    /// Change the signals read by this file descriptor. New signals are blocked, and signals
+    /// that are no longer read are unblocked.
+    pub fn set_signals(&mut self, signals: &[libc::c_int]) -> io::Result<()> {
+        let mask = sigset(signals)?;
+        let removed = self
+            .signals
+            .iter()
+            .copied()
+            .filter(|s| !signals.contains(s))
+            .collect::<Vec<_>>();
+
+        sigprocmask(libc::SIG_BLOCK, &mask)?;
+
+        // SAFETY: required for FFI; shouldn't break rust guarantees.
+        if unsafe { libc::signalfd(self.file.as_raw_fd(), &mask, 0) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        sigprocmask(libc::SIG_UNBLOCK, &sigset(&removed)?)?;
+        self.signals = signals.to_vec();
+
+        Ok(())
+    }
+
+    /// Read the next pending signal. Returns `None` if there aren't any.
+    pub fn read_signal(&self) -> io::Result<Option<SignalInfo>> {
+        let mut buf = [0u8; mem::size_of::<libc::signalfd_siginfo>()];
+
+        loop {
+            match (&self.file).read(&mut buf) {
+                Ok(n) if n == buf.len() => break,
+                Ok(_) => return Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        // SAFETY: the kernel wrote a whole `signalfd_siginfo`, which is plain old data.
+        let info: libc::signalfd_siginfo = unsafe { mem::transmute(buf) };
+
+        Ok(Some(SignalInfo {
+            signal: info.ssi_signo as libc::c_int,
+            pid: info.ssi_pid as libc::pid_t,
+            uid: info.ssi_uid,
+        }))
+    }
+}
+
+impl AsRawFd for SignalFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl AsFd for SignalFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+/// Build a signal set from a list of signals.
+fn sigset(signals: &[libc::c_int]) -> io::Result<libc::sigset_t> {
+    // SAFETY: `sigemptyset` initializes the set.
+    let mut set = unsafe { mem::zeroed() };
+
+    // SAFETY: required for FFI; shouldn't break rust guarantees.
+    unsafe { libc::sigemptyset(&mut set) };
+
+    for &signal in signals {
+        // SAFETY: required for FFI; shouldn't break rust guarantees.
+        if unsafe { libc::sigaddset(&mut set, signal) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(set)
+}
+
+/// Block or unblock a set of signals on the calling thread.
+fn sigprocmask(how: libc::c_int, set: &libc::sigset_t) -> io::Result<()> {
+    // SAFETY: required for FFI; shouldn't break rust guarantees.
+    match unsafe { libc::sigprocmask(how, set, std::ptr::null_mut()) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{interest, Sources};
+
+    use std::time::Duration;
+
+    /// Whether the given signal is blocked on the calling thread.
+    fn is_blocked(signal: libc::c_int) -> bool {
+        let mut set = sigset(&[]).unwrap();
+
+        unsafe {
+            libc::sigprocmask(libc::SIG_BLOCK, std::ptr::null(), &mut set);
+            libc::sigismember(&set, signal) == 1
+        }
+    }
+
+    #[test]
+    fn test_signalfd() -> io::Result<()> {
+        let mut sources = Sources::new();
+        let mut events = Vec::new();
+        let mut signals = SignalFd::new(&[libc::SIGUSR1])?;
+
+        sources.register_fd("signals", &signals, interest::READ);
+        assert!(is_blocked(libc::SIGUSR1));
+        assert_eq!(signals.read_signal()?, None);
+
+        unsafe { libc::raise(libc::SIGUSR1) };
+        sources.wait_timeout(&mut events, Duration::from_secs(1))?;
+
+        assert_eq!(events.len(), 1);
+        assert!(events[0].key == "signals" && events[0].is_readable());
+        assert_eq!(
+            signals.read_signal()?,
+            Some(SignalInfo {
+                signal: libc::SIGUSR1,
+                pid: unsafe { libc::getpid() },
+                uid: unsafe { libc::getuid() },
+            })
+        );
+        assert_eq!(signals.read_signal()?, None);
+
+        signals.set_signals(&[libc::SIGUSR2])?;
+        assert_eq!(signals.signals(), &[libc::SIGUSR2]);
+        assert!(is_blocked(libc::SIGUSR2));
+        assert!(!is_blocked(libc::SIGUSR1));
+
+        unsafe { libc::raise(libc::SIGUSR2) };
+        assert_eq!(
+            signals.read_signal()?.map(|i| i.signal),
+            Some(libc::SIGUSR2)
+        );
+
+        Ok(())
+    }
+}
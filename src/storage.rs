@@ -0,0 +1,281 @@
+//! Growable storage that keeps up to `N` elements inline before spilling to the heap.
+use std::fmt;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+
+/// A vector of `T` which stores its first `N` elements inline.
+///
+/// Elements are always contiguous, whether inline or on the heap, so the storage can be handed
+/// to `poll` as a plain slice.
+#[derive(Clone)]
+pub(crate) enum Storage<T, const N: usize> {
+    /// Elements are stored inline. Only the first `len` elements are in use; the rest are
+    /// defaults.
+    Inline { buf: [T; N], len: usize },
+    /// Elements are stored on the heap.
+    Heap(Vec<T>),
+}
+
+impl<T: Default, const N: usize> Storage<T, N> {
+    /// Create empty storage.
+    pub(crate) fn new() -> Self {
+        Self::Inline {
+            buf: std::array::from_fn(|_| T::default()),
+            len: 0,
+        }
+    }
+
+    /// Create empty storage able to hold `cap` elements without reallocating.
+    pub(crate) fn with_capacity(cap: usize) -> Self {
+        if cap <= N {
+            Self::new()
+        } else {
+            Self::Heap(Vec::with_capacity(cap))
+        }
+    }
+
+    /// Append an element, spilling to the heap if the inline buffer is full.
+    pub(crate) fn push(&mut self, elem: T) {
+        match self {
+            Self::Inline { buf, len } if *len < N => {
+                buf[*len] = elem;
+                *len += 1;
+            }
+            Self::Inline { buf, len } => {
+                let mut vec = Vec::with_capacity(N * 2);
+
+                vec.extend(buf[..*len].iter_mut().map(mem::take));
+                vec.push(elem);
+
+                *self = Self::Heap(vec);
+            }
+            Self::Heap(vec) => vec.push(elem),
+        }
+    }
+
+    /// Remove the last element and return it.
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        match self {
+            Self::Inline { len: 0, .. } => None,
+            Self::Inline { buf, len } => {
+                *len -= 1;
+                Some(mem::take(&mut buf[*len]))
+            }
+            Self::Heap(vec) => vec.pop(),
+        }
+    }
+
+    /// Remove the element at the given position and return it, shifting the following
+    /// elements down.
+    pub(crate) fn remove(&mut self, ix: usize) -> T {
+        match self {
+            Self::Inline { buf, len } => {
+                assert!(ix < *len, "removal index {} out of bounds", ix);
+
+                buf[ix..*len].rotate_left(1);
+                *len -= 1;
+                mem::take(&mut buf[*len])
+            }
+            Self::Heap(vec) => vec.remove(ix),
+        }
+    }
+
+    /// Shorten the storage, keeping the first `len` elements only.
+    pub(crate) fn truncate(&mut self, new_len: usize) {
+        match self {
+            Self::Inline { buf, len } => {
+                for elem in &mut buf[new_len.min(*len)..*len] {
+                    *elem = T::default();
+                }
+                *len = new_len.min(*len);
+            }
+            Self::Heap(vec) => vec.truncate(new_len),
+        }
+    }
+
+    /// Reserve capacity for at least `additional` more elements, spilling to the heap if they
+    /// don't fit inline.
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        match self {
+            Self::Inline { buf, len } if *len + additional > N => {
+                let mut vec = Vec::with_capacity(*len + additional);
+
+                vec.extend(buf[..*len].iter_mut().map(mem::take));
+                *self = Self::Heap(vec);
+            }
+            Self::Inline { .. } => {}
+            Self::Heap(vec) => vec.reserve(additional),
+        }
+    }
+
+    /// Number of elements the storage can hold without reallocating.
+    pub(crate) fn capacity(&self) -> usize {
+        match self {
+            Self::Inline { .. } => N,
+            Self::Heap(vec) => vec.capacity(),
+        }
+    }
+
+    /// Convert the storage into a vector of its elements.
+    pub(crate) fn into_vec(self) -> Vec<T> {
+        match self {
+            Self::Inline { buf, len } => buf.into_iter().take(len).collect(),
+            Self::Heap(vec) => vec,
+        }
+    }
+
+    /// Shrink the capacity of the storage as much as possible, moving the elements back
+    /// inline if they fit.
+    pub(crate) fn shrink_to_fit(&mut self) {
+        if let Self::Heap(vec) = self {
+            if vec.len() <= N {
+                let mut storage = Self::new();
+                for elem in vec.drain(..) {
+                    storage.push(elem);
+                }
+                *self = storage;
+            } else {
+                vec.shrink_to_fit();
+            }
+        }
+    }
+
+    /// Keep only the elements for which the predicate returns `true`, preserving their order.
+    pub(crate) fn retain(&mut self, mut f: impl FnMut(&T) -> bool) {
+        match self {
+            Self::Inline { buf, len } => {
+                let mut kept = 0;
+
+                for i in 0..*len {
+                    if f(&buf[i]) {
+                        buf.swap(kept, i);
+                        kept += 1;
+                    }
+                }
+                for elem in &mut buf[kept..*len] {
+                    *elem = T::default();
+                }
+                *len = kept;
+            }
+            Self::Heap(vec) => vec.retain(f),
+        }
+    }
+}
+
+impl<T, const N: usize> Deref for Storage<T, N> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            Self::Inline { buf, len } => &buf[..*len],
+            Self::Heap(vec) => vec,
+        }
+    }
+}
+
+impl<T, const N: usize> DerefMut for Storage<T, N> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        match self {
+            Self::Inline { buf, len } => &mut buf[..*len],
+            Self::Heap(vec) => vec,
+        }
+    }
+}
+
+impl<T: fmt::Debug, const N: usize> fmt::Debug for Storage<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spill() {
+        let mut storage = Storage::<usize, 4>::new();
+
+        for i in 0..4 {
+            storage.push(i);
+        }
+        assert!(matches!(storage, Storage::Inline { len: 4, .. }));
+
+        storage.push(4);
+        assert!(matches!(storage, Storage::Heap(_)));
+        assert_eq!(&storage[..], &[0, 1, 2, 3, 4]);
+
+        storage.retain(|i| i % 2 == 0);
+        assert_eq!(&storage[..], &[0, 2, 4]);
+        assert_eq!(storage.pop(), Some(4));
+
+        storage.shrink_to_fit();
+        assert!(matches!(
+            storage,
+            Storage::Inline {
+                len: 2,
+                buf: [0, 2, 0, 0]
+            }
+        ));
+        assert_eq!(storage.capacity(), 4);
+        assert_eq!(storage.into_vec(), vec![0, 2]);
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mut storage = Storage::<usize, 4>::new();
+
+        storage.push(1);
+        storage.reserve(3);
+        assert!(matches!(storage, Storage::Inline { len: 1, .. }));
+
+        storage.reserve(8);
+        assert!(matches!(storage, Storage::Heap(_)));
+        assert!(storage.capacity() >= 9);
+        assert_eq!(&storage[..], &[1]);
+    }
+
+    #[test]
+    fn test_inline() {
+        let mut storage = Storage::<usize, 4>::new();
+
+        for i in 0..4 {
+            storage.push(i);
+        }
+        storage.retain(|i| i % 2 == 1);
+        assert_eq!(&storage[..], &[1, 3]);
+        assert!(matches!(
+            storage,
+            Storage::Inline {
+                len: 2,
+                buf: [1, 3, 0, 0]
+            }
+        ));
+
+        storage.push(5);
+        assert_eq!(storage.remove(1), 3);
+        assert_eq!(&storage[..], &[1, 5]);
+        assert!(matches!(
+            storage,
+            Storage::Inline {
+                len: 2,
+                buf: [1, 5, 0, 0]
+            }
+        ));
+
+        storage.truncate(1);
+        assert!(matches!(
+            storage,
+            Storage::Inline {
+                len: 1,
+                buf: [1, 0, 0, 0]
+            }
+        ));
+        storage.push(3);
+
+        assert_eq!(storage.pop(), Some(3));
+        assert_eq!(storage.pop(), Some(1));
+        assert_eq!(storage.pop(), None);
+        assert!(storage.is_empty());
+    }
+}
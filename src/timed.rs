@@ -0,0 +1,190 @@
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
+
+use crate::{interest, Interest, Source, Timeout};
+
+/// A reader and writer that gives up on operations that don't complete in time.
+///
+/// Before each read or write, the source is polled until it is ready for it, or until the
+/// timeout for the operation expires, in which case it fails with an error of kind
+/// [`io::ErrorKind::TimedOut`]. This gives blocking I/O timeouts without an event loop.
+///
+/// # Examples
+///
+/// ```
+/// use std::io::prelude::*;
+/// use std::io;
+/// use std::os::unix::net::UnixStream;
+/// use std::time::Duration;
+///
+/// use popol::{Timed, Timeout};
+///
+/// let (_writer, reader) = UnixStream::pair()?;
+/// let mut reader = Timed::new(reader, Duration::from_millis(1), Timeout::Never);
+///
+/// let err = reader.read(&mut [0; 32]).unwrap_err();
+/// assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+/// # Ok::<(), io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Timed<T> {
+    inner: T,
+    read_timeout: Timeout,
+    write_timeout: Timeout,
+}
+
+impl<T: AsRawFd> Timed<T> {
+    /// Wrap a source, with the given read and write timeouts.
+    pub fn new(
+        inner: T,
+        read_timeout: impl Into<Timeout>,
+        write_timeout: impl Into<Timeout>,
+    ) -> Self {
+        Self {
+            inner,
+            read_timeout: read_timeout.into(),
+            write_timeout: write_timeout.into(),
+        }
+    }
+
+    /// The timeout for reads.
+    pub fn read_timeout(&self) -> &Timeout {
+        &self.read_timeout
+    }
+
+    /// The timeout for writes.
+    pub fn write_timeout(&self) -> &Timeout {
+        &self.write_timeout
+    }
+
+    /// Set the timeout for reads. Takes effect from the next read.
+    pub fn set_read_timeout(&mut self, timeout: impl Into<Timeout>) {
+        self.read_timeout = timeout.into();
+    }
+
+    /// Set the timeout for writes. Takes effect from the next write.
+    pub fn set_write_timeout(&mut self, timeout: impl Into<Timeout>) {
+        self.write_timeout = timeout.into();
+    }
+
+    /// The wrapped source.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// The wrapped source, mutably. Reading or writing through it bypasses the timeouts.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Unwrap the source.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Wait until the source is ready for the given events, or the timeout expires.
+    fn ready(&self, events: Interest, timeout: &Timeout) -> io::Result<()> {
+        let mut source = [Source::new(self.inner.as_raw_fd(), events)];
+
+        match crate::poll(&mut source, timeout.clone(), None)? {
+            0 => Err(io::ErrorKind::TimedOut.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Run an operation once the source is ready for it, retrying once if it would block.
+    fn with<R>(
+        &mut self,
+        events: Interest,
+        timeout: Timeout,
+        mut op: impl FnMut(&mut T) -> io::Result<R>,
+    ) -> io::Result<R> {
+        self.ready(events, &timeout)?;
+
+        match op(&mut self.inner) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.ready(events, &timeout)?;
+                op(&mut self.inner)
+            }
+            result => result,
+        }
+    }
+}
+
+impl<T: AsRawFd + Read> Read for Timed<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = self.read_timeout.clone();
+
+        self.with(interest::READ, timeout, |inner| inner.read(buf))
+    }
+}
+
+impl<T: AsRawFd + Write> Write for Timed<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let timeout = self.write_timeout.clone();
+
+        self.with(interest::WRITE, timeout, |inner| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixStream;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_read_timeout() -> io::Result<()> {
+        let (mut writer, reader) = UnixStream::pair()?;
+        let mut reader = Timed::new(reader, Duration::from_millis(50), Timeout::Never);
+        let mut buf = [0; 8];
+
+        let start = Instant::now();
+        let err = reader.read(&mut buf).unwrap_err();
+        let elapsed = start.elapsed();
+
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(elapsed >= Duration::from_millis(50));
+        assert!(elapsed < Duration::from_secs(1));
+
+        writer.write_all(&[1, 2])?;
+        reader.set_read_timeout(Duration::from_secs(6));
+
+        let start = Instant::now();
+        assert_eq!(reader.read(&mut buf)?, 2);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_timeout() -> io::Result<()> {
+        let (writer, _reader) = UnixStream::pair()?;
+        let buf = [0; 4096];
+
+        writer.set_nonblocking(true)?;
+        let mut writer = Timed::new(writer, Timeout::Never, Duration::from_millis(50));
+
+        // Fill the socket buffer.
+        loop {
+            match writer.get_mut().write(&buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+                _ => continue,
+            }
+        }
+        let start = Instant::now();
+        let err = writer.write(&buf).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        Ok(())
+    }
+}
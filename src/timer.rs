@@ -0,0 +1,179 @@
+//! Timers that are registered like any other source, backed by `timerfd` on Linux and Android.
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
+use std::time::Duration;
+
+/// A timer that becomes readable when it expires.
+///
+/// Register it for [`crate::interest::READ`], and call [`TimerFd::read_expirations`] when it
+/// is readable. It is non-blocking, and closed on `exec`.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use popol::{interest, timer::TimerFd, Sources, Timeout};
+///
+/// let mut sources = Sources::new();
+/// let mut events = Vec::new();
+/// let timer = TimerFd::new()?;
+///
+/// sources.register_fd("timer", &timer, interest::READ);
+/// timer.set_oneshot(Duration::from_millis(1))?;
+/// sources.poll(&mut events, Timeout::Never)?;
+///
+/// assert_eq!(events[0].key, "timer");
+/// assert_eq!(timer.read_expirations()?, 1);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct TimerFd {
+    file: File,
+}
+
+impl TimerFd {
+    /// Create a disarmed timer, measured against the monotonic clock.
+    pub fn new() -> io::Result<Self> {
+        // SAFETY: required for FFI; shouldn't break rust guarantees.
+        match unsafe {
+            libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_NONBLOCK | libc::TFD_CLOEXEC,
+            )
+        } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(Self {
+                // SAFETY: the file descriptor was just created, and is owned by nothing else.
+                file: unsafe { File::from_raw_fd(fd) },
+            }),
+        }
+    }
+
+    /// Arm the timer to expire once, after the given duration. A zero duration expires right
+    /// away.
+    ///
+    /// Rearming the timer discards the expirations that weren't read yet.
+    pub fn set_oneshot(&self, after: Duration) -> io::Result<()> {
+        self.set(after, Duration::ZERO)
+    }
+
+    /// Arm the timer to expire every time the given interval elapses, starting one interval
+    /// from now. A zero interval expires right away, and then never again.
+    ///
+    /// Rearming the timer discards the expirations that weren't read yet.
+    pub fn set_interval(&self, interval: Duration) -> io::Result<()> {
+        self.set(interval, interval)
+    }
+
+    /// Disarm the timer, and discard the expirations that weren't read yet.
+    pub fn disarm(&self) -> io::Result<()> {
+        self.settime(Duration::ZERO, Duration::ZERO)
+    }
+
+    /// Read the number of times the timer expired since it was last read, or armed. Returns
+    /// `0` if it hasn't expired.
+    pub fn read_expirations(&self) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+
+        match (&self.file).read(&mut buf) {
+            Ok(8) => Ok(u64::from_ne_bytes(buf)),
+            Ok(_) => Ok(0),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Arm the timer. A zero initial expiration disarms a `timerfd`, so it is rounded up.
+    fn set(&self, after: Duration, interval: Duration) -> io::Result<()> {
+        self.settime(after.max(Duration::from_nanos(1)), interval)
+    }
+
+    /// Set the timer's expirations, discarding the pending ones.
+    fn settime(&self, value: Duration, interval: Duration) -> io::Result<()> {
+        let spec = libc::itimerspec {
+            it_interval: timespec(interval),
+            it_value: timespec(value),
+        };
+        // SAFETY: required for FFI; shouldn't break rust guarantees.
+        match unsafe {
+            libc::timerfd_settime(self.file.as_raw_fd(), 0, &spec, std::ptr::null_mut())
+        } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl AsRawFd for TimerFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl AsFd for TimerFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+/// Convert a duration to a `timespec`, saturating at the largest number of seconds.
+fn timespec(duration: Duration) -> libc::timespec {
+    libc::timespec {
+        tv_sec: duration.as_secs().try_into().unwrap_or(libc::time_t::MAX),
+        tv_nsec: duration.subsec_nanos() as libc::c_long,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{interest, Sources, Timeout};
+
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_oneshot() -> io::Result<()> {
+        let mut sources = Sources::new();
+        let mut events = Vec::new();
+        let timer = TimerFd::new()?;
+
+        sources.register_fd("timer", &timer, interest::READ);
+        assert_eq!(timer.read_expirations()?, 0);
+
+        let start = Instant::now();
+        timer.set_oneshot(Duration::from_millis(20))?;
+        sources.poll(&mut events, Timeout::Never)?;
+
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(events.len(), 1);
+        assert!(events[0].key == "timer" && events[0].is_readable());
+        assert_eq!(timer.read_expirations()?, 1);
+        assert_eq!(timer.read_expirations()?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interval() -> io::Result<()> {
+        let timer = TimerFd::new()?;
+
+        timer.set_interval(Duration::from_millis(10))?;
+        thread::sleep(Duration::from_millis(55));
+        assert!(timer.read_expirations()? >= 5);
+
+        // Expirations pending when the timer is rearmed or disarmed are discarded.
+        thread::sleep(Duration::from_millis(15));
+        timer.set_oneshot(Duration::from_secs(60))?;
+        assert_eq!(timer.read_expirations()?, 0);
+
+        timer.set_oneshot(Duration::ZERO)?;
+        thread::sleep(Duration::from_millis(5));
+        timer.disarm()?;
+        assert_eq!(timer.read_expirations()?, 0);
+
+        Ok(())
+    }
+}
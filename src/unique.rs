@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use crate::{interest, Compaction, Event, Interest, Source, Sources, Timeout, Waker};
+
+/// Keeps track of sources to poll, with keys that are guaranteed to be unique.
+///
+/// Registering a source with a key that is already in use replaces the previous source,
+/// instead of adding a second one. Keys are looked up in a hash map, so registering,
+/// unregistering and changing interests don't depend on the number of sources.
+#[derive(Debug, Clone)]
+pub struct UniquePoll<K> {
+    /// Sources to poll. They are never compacted, so that slots stay valid.
+    sources: Sources<K>,
+    /// The slot of each key's source.
+    slots: HashMap<K, usize>,
+}
+
+impl<K: Eq + Hash + Clone> UniquePoll<K> {
+    /// Creates a new set of sources to poll.
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    /// Creates a new set of sources to poll, with the given capacity.
+    pub fn with_capacity(cap: usize) -> Self {
+        let mut sources = Sources::with_capacity(cap);
+        sources.set_compaction(Compaction::Never);
+
+        Self {
+            sources,
+            slots: HashMap::with_capacity(cap),
+        }
+    }
+
+    /// Return the number of registered sources.
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Return whether the source registry is empty.
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Register a new source, with the given key, and wait for the specified events.
+    ///
+    /// If a source was already registered with this key, it is replaced, and returned.
+    pub fn register(
+        &mut self,
+        key: K,
+        fd: &impl AsRawFd,
+        events: impl Into<Interest>,
+    ) -> Option<Source> {
+        let source = Source::registered(fd, events.into());
+
+        if let Some(ix) = self.slots.get(&key) {
+            return Some(self.sources.replace(*ix, source));
+        }
+        let ix = self.sources.insert(key.clone(), source);
+        self.slots.insert(key, ix);
+
+        None
+    }
+
+    /// Unregister a source, given its key. Returns the source, if it was registered.
+    pub fn unregister(&mut self, key: &K) -> Option<Source> {
+        let ix = self.slots.remove(key)?;
+        let source = self.sources.list[ix];
+
+        self.sources.remove(ix);
+
+        Some(source)
+    }
+
+    /// Add events to poll for on a source identified by its key.
+    pub fn add_interest(&mut self, key: &K, events: impl Into<Interest>) -> bool {
+        match self.slots.get(key) {
+            Some(ix) => self
+                .sources
+                .add_interest_by_id(self.sources.id(*ix), events),
+            None => false,
+        }
+    }
+
+    /// Remove events to poll for on a source identified by its key.
+    pub fn remove_interest(&mut self, key: &K, events: impl Into<Interest>) -> bool {
+        match self.slots.get(key) {
+            Some(ix) => self
+                .sources
+                .remove_interest_by_id(self.sources.id(*ix), events),
+            None => false,
+        }
+    }
+
+    /// Get a source by key.
+    pub fn get(&self, key: &K) -> Option<&Source> {
+        self.slots.get(key).map(|ix| &self.sources.list[*ix])
+    }
+
+    /// Get a source by key, mutably. See [`Sources::get_mut`].
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut Source> {
+        let ix = *self.slots.get(key)?;
+        Some(self.sources.source_mut(ix))
+    }
+
+    /// Create a [`Waker`], and register it with the given key. If a source was already
+    /// registered with this key, it is replaced.
+    pub fn waker(&mut self, key: K) -> io::Result<Waker> {
+        let waker = Waker::unregistered()?;
+        self.register(key, &waker.reader, interest::READ);
+
+        Ok(waker)
+    }
+
+    /// Wait for readiness events on the registered sources. See [`Sources::poll`].
+    pub fn poll(
+        &mut self,
+        events: &mut Vec<Event<K>>,
+        timeout: impl Into<Timeout>,
+    ) -> Result<usize, io::Error> {
+        self.sources.poll(events, timeout)
+    }
+
+    /// Wait for readiness events on the registered sources, with a timeout.
+    /// See [`Sources::wait_timeout`].
+    pub fn wait_timeout(
+        &mut self,
+        events: &mut Vec<Event<K>>,
+        timeout: Duration,
+    ) -> Result<usize, io::Error> {
+        self.poll(events, timeout)
+    }
+
+    /// Wait for readiness events on the registered sources, or until the call is interrupted.
+    /// See [`Sources::wait`].
+    pub fn wait(&mut self, events: &mut Vec<Event<K>>) -> Result<usize, io::Error> {
+        self.poll(events, Timeout::Never)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::prelude::*;
+    use std::os::unix::net::UnixStream;
+
+    /// Run the cases shared by every registry against the given registries, each in its own
+    /// module. Each registry is given with a function creating a waker for it.
+    macro_rules! shared_tests {
+        ($($name:ident: $new:expr, $waker:expr;)*) => {$(
+            mod $name {
+                use super::*;
+
+                #[test]
+                fn test_register() -> io::Result<()> {
+                    let (mut writer0, reader0) = UnixStream::pair()?;
+                    let (mut writer1, reader1) = UnixStream::pair()?;
+                    let mut events = Vec::new();
+                    let mut sources = $new;
+
+                    sources.register("reader0", &reader0, interest::READ);
+                    sources.register("reader1", &reader1, interest::READ);
+                    assert_eq!(sources.len(), 2);
+
+                    writer0.write_all(&[1])?;
+                    writer1.write_all(&[1])?;
+                    assert_eq!(sources.poll(&mut events, Timeout::from_secs(1))?, 2);
+
+                    let mut keys = events.iter().map(|e| e.key).collect::<Vec<_>>();
+                    keys.sort_unstable();
+                    assert_eq!(keys, vec!["reader0", "reader1"]);
+                    assert!(events.iter().all(|e| e.is_readable()));
+
+                    Ok(())
+                }
+
+                #[test]
+                fn test_unregister() -> io::Result<()> {
+                    let (mut writer0, reader0) = UnixStream::pair()?;
+                    let (mut writer1, reader1) = UnixStream::pair()?;
+                    let mut events = Vec::new();
+                    let mut sources = $new;
+
+                    sources.register("reader0", &reader0, interest::READ);
+                    sources.register("reader1", &reader1, interest::READ);
+                    assert_eq!(
+                        sources.unregister(&"reader0").map(|s| s.as_raw_fd()),
+                        Some(reader0.as_raw_fd())
+                    );
+                    assert!(sources.unregister(&"reader0").is_none());
+                    assert!(sources.get(&"reader0").is_none());
+                    assert_eq!(sources.len(), 1);
+
+                    writer0.write_all(&[1])?;
+                    writer1.write_all(&[1])?;
+                    assert_eq!(sources.poll(&mut events, Timeout::from_secs(1))?, 1);
+                    assert_eq!(events[0].key, "reader1");
+
+                    Ok(())
+                }
+
+                #[test]
+                fn test_interests() -> io::Result<()> {
+                    let (mut writer, reader) = UnixStream::pair()?;
+                    let mut events = Vec::new();
+                    let mut sources = $new;
+
+                    sources.register("reader", &reader, interest::NONE);
+                    writer.write_all(&[1])?;
+
+                    let err = sources
+                        .poll(&mut events, Timeout::from_millis(1))
+                        .unwrap_err();
+                    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+                    assert!(sources.add_interest(&"reader", interest::ALL));
+                    assert!(sources.remove_interest(&"reader", interest::WRITE));
+                    assert!(!sources.add_interest(&"other", interest::READ));
+                    assert_eq!(sources.get(&"reader").unwrap().interest(), interest::READ);
+
+                    assert_eq!(sources.poll(&mut events, Timeout::from_secs(1))?, 1);
+                    assert!(events[0].key == "reader" && events[0].is_readable());
+
+                    // Changes made through a mutable reference are polled for too.
+                    sources.get_mut(&"reader").unwrap().remove(interest::READ);
+                    events.clear();
+                    let err = sources
+                        .poll(&mut events, Timeout::from_millis(1))
+                        .unwrap_err();
+                    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+                    assert!(events.is_empty());
+
+                    Ok(())
+                }
+
+                #[test]
+                fn test_waker() -> io::Result<()> {
+                    let mut events = Vec::new();
+                    let mut sources = $new;
+                    let waker = $waker(&mut sources, "waker")?;
+
+                    waker.wake()?;
+                    assert_eq!(sources.poll(&mut events, Timeout::from_secs(1))?, 1);
+                    assert!(events[0].key == "waker" && events[0].is_readable());
+
+                    Ok(())
+                }
+
+                #[test]
+                fn test_timeout() -> io::Result<()> {
+                    let (_writer, reader) = UnixStream::pair()?;
+                    let mut events = Vec::new();
+                    let mut sources = $new;
+
+                    // An empty registry waits for the timeout, and reports no events.
+                    assert_eq!(sources.poll(&mut events, Timeout::from_millis(1))?, 0);
+
+                    sources.register("reader", &reader, interest::READ);
+                    let err = sources
+                        .wait_timeout(&mut events, Duration::from_millis(1))
+                        .unwrap_err();
+                    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+                    assert!(events.is_empty());
+
+                    Ok(())
+                }
+            }
+        )*};
+    }
+
+    shared_tests! {
+        sources: Sources::new(), |s: &mut Sources<_>, key| Waker::new(s, key);
+        unique: UniquePoll::new(), |s: &mut UniquePoll<_>, key| s.waker(key);
+    }
+
+    #[test]
+    fn test_replace() -> io::Result<()> {
+        let (mut writer0, reader0) = UnixStream::pair()?;
+        let (mut writer1, reader1) = UnixStream::pair()?;
+        let mut events = Vec::new();
+        let mut sources = UniquePoll::new();
+
+        assert!(sources
+            .register("reader", &reader0, interest::READ)
+            .is_none());
+
+        let previous = sources
+            .register("reader", &reader1, interest::READ)
+            .unwrap();
+        assert_eq!(previous.as_raw_fd(), reader0.as_raw_fd());
+        assert_eq!(sources.len(), 1);
+
+        // Only the replacement is polled.
+        writer0.write_all(&[1])?;
+        let err = sources
+            .poll(&mut events, Timeout::from_millis(1))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        writer1.write_all(&[1])?;
+        assert_eq!(sources.poll(&mut events, Timeout::from_millis(1))?, 1);
+        assert_eq!(events[0].key, "reader");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unregister() -> io::Result<()> {
+        let pairs = (0..4)
+            .map(|_| UnixStream::pair())
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut events = Vec::new();
+        let mut sources = UniquePoll::new();
+
+        for (key, (_, reader)) in pairs.iter().enumerate() {
+            sources.register(key, reader, interest::READ);
+        }
+        assert_eq!(
+            sources.unregister(&1).map(|s| s.as_raw_fd()),
+            Some(pairs[1].1.as_raw_fd())
+        );
+        assert!(sources.unregister(&1).is_none());
+        assert!(!sources.add_interest(&1, interest::WRITE));
+
+        // Free slots are reused, and other keys still point to their source.
+        sources.register(4, &pairs[1].1, interest::READ);
+        sources.remove_interest(&0, interest::READ);
+
+        for mut writer in pairs.iter().map(|(writer, _)| writer) {
+            writer.write_all(&[1])?;
+        }
+        sources.poll(&mut events, Timeout::from_millis(1))?;
+
+        let mut keys = events.iter().map(|e| e.key).collect::<Vec<_>>();
+        keys.sort_unstable();
+        assert_eq!(keys, vec![2, 3, 4]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_waker() -> io::Result<()> {
+        let mut events = Vec::new();
+        let mut sources = UniquePoll::new();
+        let waker = sources.waker("waker")?;
+
+        waker.wake()?;
+        sources.poll(&mut events, Timeout::from_millis(1))?;
+        assert_eq!(events[0].key, "waker");
+        assert!(events[0].is_readable());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+
+use crate::{Interest, Source, Waker};
+
+/// A change to a registry, queued by an [`InterestUpdater`].
+#[derive(Debug)]
+pub(crate) enum Update<K> {
+    /// Register a source.
+    Register(K, Source),
+    /// Set interests on a source.
+    Set(K, Interest),
+    /// Unset interests on a source.
+    Unset(K, Interest),
+    /// Unregister a source.
+    Unregister(K),
+}
+
+impl<K> Update<K> {
+    /// The key of the source this update applies to.
+    pub(crate) fn into_key(self) -> K {
+        match self {
+            Self::Register(key, _)
+            | Self::Set(key, _)
+            | Self::Unset(key, _)
+            | Self::Unregister(key) => key,
+        }
+    }
+}
+
+/// Updates shared between a registry and its updaters.
+#[derive(Debug)]
+pub(crate) struct Updates<K> {
+    /// Updates not yet applied, in the order they were made.
+    pub(crate) queue: Mutex<Vec<Update<K>>>,
+    /// Wakes up the registry when an update is queued.
+    pub(crate) waker: Waker,
+}
+
+impl<K> Updates<K> {
+    /// Take all queued updates.
+    pub(crate) fn take(&self) -> Vec<Update<K>> {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        std::mem::take(&mut *queue)
+    }
+
+    /// Whether no updates are queued.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queue
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_empty()
+    }
+}
+
+/// Changes the interests of sources from other threads.
+///
+/// Updates are queued, and the registry they belong to is woken up. They are applied at the
+/// start of the next call to [`crate::Sources::poll`], in the order they were made.
+///
+/// Created with [`crate::Sources::updater`].
+#[derive(Debug)]
+pub struct InterestUpdater<K> {
+    pub(crate) updates: Arc<Updates<K>>,
+}
+
+impl<K> Clone for InterestUpdater<K> {
+    fn clone(&self) -> Self {
+        Self {
+            updates: self.updates.clone(),
+        }
+    }
+}
+
+impl<K> InterestUpdater<K> {
+    /// Register a new source, with the given key, and wait for the specified events.
+    ///
+    /// The file descriptor must stay open until the registration is applied.
+    pub fn register(
+        &self,
+        key: K,
+        fd: &impl AsRawFd,
+        events: impl Into<Interest>,
+    ) -> io::Result<()> {
+        self.push(Update::Register(key, Source::registered(fd, events.into())))
+    }
+
+    /// Add events to poll for on a source identified by its key.
+    pub fn add_interest(&self, key: K, events: impl Into<Interest>) -> io::Result<()> {
+        self.push(Update::Set(key, events.into()))
+    }
+
+    /// Remove events to poll for on a source identified by its key.
+    pub fn remove_interest(&self, key: K, events: impl Into<Interest>) -> io::Result<()> {
+        self.push(Update::Unset(key, events.into()))
+    }
+
+    /// Unregister a source, given its key.
+    pub fn unregister(&self, key: K) -> io::Result<()> {
+        self.push(Update::Unregister(key))
+    }
+
+    fn push(&self, update: Update<K>) -> io::Result<()> {
+        self.updates
+            .queue
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(update);
+        self.updates.waker.wake()
+    }
+}